@@ -1,19 +1,542 @@
-use clap::{ArgMatches, Args, Command, FromArgMatches};
+use clap::{ArgAction, ArgMatches, Args, Command, FromArgMatches, ValueEnum};
 use glib::markup_escape_text;
-use std::{collections::HashMap, env, error, fmt, io, io::prelude::*, process};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env, error,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    io::prelude::*,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, OpenOptionsExt},
+        io::{AsFd, AsRawFd},
+        net::{UnixListener, UnixStream},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use urlencoding::decode;
+use zeroize::Zeroizing;
+
+#[cfg(any(test, feature = "testing", feature = "fuzz"))]
+pub mod testing;
 
 pub type BoxResult<T> = Result<T, Box<dyn error::Error>>;
 
+/// Error source of pinentry programs in libgpg-error (`GPG_ERR_SOURCE_PINENTRY`)
+pub const GPG_ERR_SOURCE_PINENTRY: u32 = 5;
+/// General error (`GPG_ERR_GENERAL`)
+pub const GPG_ERR_GENERAL: u32 = 1;
+/// Operation cancelled (`GPG_ERR_CANCELED`)
+pub const GPG_ERR_CANCELED: u32 = 99;
+/// Not confirmed (`GPG_ERR_NOT_CONFIRMED`)
+pub const GPG_ERR_NOT_CONFIRMED: u32 = 114;
+/// No pinentry (`GPG_ERR_NO_PIN_ENTRY`)
+pub const GPG_ERR_NO_PIN_ENTRY: u32 = 85;
+/// Not supported (`GPG_ERR_NOT_SUPPORTED`)
+pub const GPG_ERR_NOT_SUPPORTED: u32 = 60;
+/// Timeout (`GPG_ERR_TIMEOUT`)
+pub const GPG_ERR_TIMEOUT: u32 = 62;
+/// Error reading from the Assuan peer (`GPG_ERR_ASS_READ_ERROR`)
+pub const GPG_ERR_ASS_READ_ERROR: u32 = 270;
+/// Unknown Assuan command (`GPG_ERR_ASS_UNKNOWN_CMD`)
+pub const GPG_ERR_ASS_UNKNOWN_CMD: u32 = 275;
+/// IPC syntax error (`GPG_ERR_ASS_SYNTAX`)
+pub const GPG_ERR_ASS_SYNTAX: u32 = 276;
+/// Malformed command argument (`GPG_ERR_ASS_PARAMETER`)
+pub const GPG_ERR_ASS_PARAMETER: u32 = 280;
+
+/// Version reported by `GETINFO version`
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Flavor reported by `GETINFO flavor`
+pub fn flavor() -> &'static str {
+    "keyring"
+}
+
+/// Compose a libgpg-error value from its source and code, like `gpg_err_make` does.
+pub fn gpg_error(source: u32, code: u32) -> u32 {
+    ((source & 0x7f) << 24) | (code & 0xffff)
+}
+
 #[derive(Debug, Args)]
 struct RofiArgs {
     /// Set display
     #[arg(short, long, default_value = ":0", env)]
     display: String,
 
+    /// rofi executable to run, looked up in PATH unless it contains a `/`
+    #[arg(long, value_name = "BINARY", default_value = "rofi")]
+    rofi_bin: String,
+
     /// Set rofi prompt
     #[arg(short, long, env = "PINENTRY_USER_DATA")]
     prompt: Option<String>,
+
+    /// Prompt shown by GETPIN, with `{prompt}`, `{host}`, `{user}` and `{keygrip}` filled in
+    #[arg(long, value_name = "TEMPLATE")]
+    prompt_template: Option<String>,
+
+    /// Strip control characters and collapse whitespace in the prompt
+    #[arg(long, action = ArgAction::Set, default_value_t = true, value_name = "BOOL")]
+    sanitize_prompt: bool,
+
+    /// Shell command to run before showing the prompt
+    #[arg(long)]
+    pre_cmd: Option<String>,
+
+    /// Shell command to run after the prompt is closed
+    #[arg(long)]
+    post_cmd: Option<String>,
+
+    /// Shell command run in the background as each GETPIN prompt comes up, e.g. `notify-send`,
+    /// to notice a passphrase request on another workspace. Failing is not fatal.
+    #[arg(long, value_name = "COMMAND")]
+    notify: Option<String>,
+
+    /// Truncate descriptions longer than this many bytes
+    #[arg(long, default_value_t = 4096)]
+    max_desc_len: usize,
+
+    /// Paragraph put before every SETDESC, e.g. the host name or a reminder. It counts
+    /// towards `--max-desc-len`.
+    #[arg(long, value_name = "TEXT")]
+    desc_prefix: Option<String>,
+
+    /// Show at most this many description lines, so a tall one can't push rofi off the screen
+    #[arg(long, value_name = "N")]
+    desc_max_lines: Option<usize>,
+
+    /// Hand the whole session over to another pinentry (e.g. `pinentry-curses`) when the display is unavailable
+    #[arg(long, value_name = "BINARY")]
+    delegate_to: Option<String>,
+
+    /// Always start rofi, even when no X11 socket or Wayland session is found for the display,
+    /// e.g. for X servers on abstract sockets or in another network namespace
+    #[arg(long)]
+    no_display_check: bool,
+
+    /// Only accept digits for smartcard PIN prompts, as `OPTION numeric-pin` does. Off by
+    /// default since OpenPGP card PINs may contain letters.
+    #[arg(long)]
+    numeric_card_pin: bool,
+
+    /// Set rofi matching mode for dialogs with several entries
+    #[arg(long, value_enum)]
+    matching: Option<Matching>,
+
+    /// Escape SETERROR text like the description instead of passing it to rofi as markup
+    #[arg(long)]
+    no_markup_in_error: bool,
+
+    /// How markup in SETDESC is shown
+    #[arg(long, value_enum, default_value_t = DescMarkup::Escape)]
+    desc_markup: DescMarkup,
+
+    /// rofi key binding that reveals the passphrase being typed (e.g. `Alt+v`)
+    #[arg(long, value_name = "KEY")]
+    kb_show_passphrase: Option<String>,
+
+    /// rofi key binding that shows the full passphrase rules from `OPTION constraints-hint-long`,
+    /// leaving only the short hint under the prompt
+    #[arg(long, value_name = "KEY")]
+    kb_constraints_help: Option<String>,
+
+    /// Answer ERR to arguments with bad percent escapes or raw control characters
+    #[arg(long)]
+    assuan_strict_encoding: bool,
+
+    /// Terminate the pinentry-rofi still prompting from an earlier request before taking over
+    #[arg(long)]
+    kill_existing: bool,
+
+    /// Keep our pid in this file while running, instead of the one in `$XDG_RUNTIME_DIR` that
+    /// `--kill-existing` uses otherwise
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Say BYE and exit on an unknown command instead of answering ERR and carrying on
+    #[arg(long)]
+    exit_on_first_error: bool,
+
+    /// OPTIONs allowed to set their environment variable (GPG_TTY, GPG_TERM, LC_CTYPE, LC_MESSAGES)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "ttyname,ttytype,lc-ctype,lc-messages",
+        value_parser = ENV_OPTIONS.map(|(option, _)| option)
+    )]
+    env_options: Vec<String>,
+
+    /// Never modify the environment from OPTIONs
+    #[arg(long)]
+    no_env_options: bool,
+
+    /// Put back the variables OPTIONs changed once the session (each `--listen` connection) ends
+    #[arg(long)]
+    reset_env_on_exit: bool,
+
+    /// Show the prompt as the passphrase entry placeholder instead of rofi's prompt label
+    #[arg(long)]
+    prompt_as_password_placeholder: bool,
+
+    /// Text shown in the empty passphrase entry, next to the prompt label
+    #[arg(long, value_name = "TEXT", conflicts_with = "prompt_as_password_placeholder")]
+    entry_placeholder: Option<String>,
+
+    /// Signal that stops rofi when the SETTIMEOUT expires
+    #[arg(long, value_enum, default_value_t = TimeoutSignal::Term)]
+    rofi_timeout_signal: TimeoutSignal,
+
+    /// Timeout for confirmation dialogs, instead of the one from SETTIMEOUT
+    #[arg(long, value_name = "SECS")]
+    confirm_timeout: Option<u64>,
+
+    /// How long rofi gets to exit after SIGTERM before it is killed
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    rofi_grace_ms: u64,
+
+    /// Close the connection once it has been open this long, checked between commands so a
+    /// prompt on screen is never interrupted
+    #[arg(long, value_name = "SECS")]
+    max_connections_lifetime: Option<u64>,
+
+    /// Serve Assuan on this Unix socket, one connection at a time, instead of on stdin and stdout
+    #[arg(long, value_name = "SOCKET")]
+    listen: Option<PathBuf>,
+
+    /// Give up on the session when gpg-agent hasn't taken a response off stdout for this long,
+    /// instead of hanging on a wedged pipe
+    #[arg(long, value_name = "MS")]
+    flush_timeout: Option<u64>,
+
+    /// Buffer Assuan responses and write them out once per command instead of once per line
+    #[arg(long)]
+    no_flush_per_line: bool,
+
+    /// How much to log, to stderr or `--log-file`. Assuan traffic is logged at debug, with data
+    /// lines redacted.
+    #[arg(long, value_enum, default_value_t = LogLevel::Error)]
+    log_level: LogLevel,
+
+    /// Append the log to this file, created private to the user, instead of writing it to stderr
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Have rofi print this marker after the passphrase, and reject output cut short before it
+    #[arg(long, value_name = "MARKER", value_parser = parse_end_marker)]
+    end_marker: Option<String>,
+
+    /// Read the passphrase through a fixed buffer and give up on anything longer than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_pin_len: Option<usize>,
+
+    /// Ask again until exactly this many digits are entered, for one-time codes
+    #[arg(long, value_name = "N")]
+    numeric_len: Option<usize>,
+
+    /// Ask again for a new passphrase shorter than this many characters. Unlocking is never affected.
+    #[arg(long, value_name = "N")]
+    password_min_length: Option<usize>,
+
+    /// File with per-key prompts: `[KEYGRIP]` sections with `prompt = ...` and `theme-str = ...`.
+    /// `[key]` and `[symmetric]` sections apply to other key unlocks and to symmetric passphrases.
+    #[arg(long, value_name = "FILE")]
+    keygrip_map: Option<PathBuf>,
+
+    /// Make GETPIN a choice among the lines of this file (or `/dev/fd/N`) and answer the picked one
+    #[arg(long, value_name = "FILE")]
+    entries_from_file: Option<PathBuf>,
+
+    /// Show the passphrase attempts as a `●●○` counter above the message
+    #[arg(long)]
+    show_attempts: bool,
+
+    /// Line separator used in the rofi message
+    #[arg(long, value_enum, default_value_t = NewlineRepr::Cr)]
+    newline_repr: NewlineRepr,
+
+    /// Have rofi take the input focus and hand it back when done (`-steal-focus`), for focus-follows-mouse setups
+    #[arg(long)]
+    foreground: bool,
+
+    /// Show rofi as a normal window (`-normal-window`) that doesn't grab the keyboard, so input
+    /// methods keep working. Other clients can then read keystrokes; `OPTION grab` is ignored.
+    #[arg(long)]
+    ime_friendly: bool,
+
+    /// Start each prompt and message line with a directional mark, for right-to-left languages.
+    /// `auto` picks the direction of the first letter.
+    #[arg(long, value_enum)]
+    prompt_bidi: Option<PromptBidi>,
+
+    /// After each GETPIN, check that rofi left its history files in the cache directory alone,
+    /// and warn if a rofi config overrode `-disable-history`
+    #[arg(long)]
+    disable_history_verify: bool,
+
+    /// Log what rofi prints to stderr into `--log-file`, at warn level whatever `--log-level`
+    /// says. It still makes up the error detail when rofi fails.
+    #[arg(long, requires = "log_file")]
+    redirect_stderr_to_log: bool,
+
+    /// Write `ok`, `cancel` or `error` here after each GETPIN and CONFIRM, for wrapper scripts.
+    /// The passphrase never goes into it.
+    #[arg(long, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+
+    /// Monitor to show rofi on: a rofi `-m` number, or a connector name like `DP-1` checked
+    /// against `xrandr --listmonitors` when xrandr is available
+    #[arg(long, value_name = "MONITOR")]
+    monitor: Option<String>,
+
+    /// Point rofi's `-input` at an empty temp file of this run's own, removed on exit, for systems
+    /// where rofi can't use `/dev/null`
+    #[arg(long)]
+    prompt_history_salt: bool,
+
+    /// rofi config file used instead of the interactive one (`-config`)
+    #[arg(long, value_name = "FILE")]
+    rofi_config: Option<PathBuf>,
+
+    /// Prompt text color, `#rgb[a]`, `#rrggbb[aa]` or a name like `red`
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    prompt_color: Option<String>,
+
+    /// Message text color, `#rgb[a]`, `#rrggbb[aa]` or a name like `red`
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    mesg_color: Option<String>,
+
+    /// Make the rofi window this many characters wide, so rofi wraps the message itself instead
+    /// of it stretching the window
+    #[arg(long, value_name = "CHARS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_mesg_width: Option<u32>,
+
+    /// Extra rofi `-theme-str`, passed as is. Can be repeated.
+    #[arg(long, value_name = "THEME")]
+    theme_str: Vec<String>,
+
+    /// Button CONFIRM starts on. Without it, rofi's own selection: the first row, OK.
+    #[arg(long, value_enum)]
+    confirm_default_focus: Option<ConfirmFocus>,
+
+    /// Send `S CONFIRM_OK` or `S CONFIRM_CANCEL` before answering a CONFIRM whose button was picked
+    #[arg(long)]
+    confirm_status_line: bool,
+
+    /// INSECURE, for CI only: answer GETPIN with the value of this environment variable instead
+    /// of asking
+    #[cfg(feature = "insecure-testing")]
+    #[arg(long, value_name = "VAR")]
+    insecure_pin_from_env: Option<String>,
+
+    /// How to answer a CONFIRM submitted empty or with text that is neither button
+    #[arg(long, value_enum, default_value_t = ConfirmOnEmpty::Cancel)]
+    confirm_on_empty: ConfirmOnEmpty,
+
+    /// Answer CONFIRM without showing rofi, for unattended use. GETPIN always asks.
+    #[arg(long, value_enum, default_value_t = AutoConfirm::Never)]
+    auto_confirm: AutoConfirm,
+
+    /// Drop the `SETTITLE` title once a dialog has shown it and another key or description
+    /// comes along, instead of keeping it for the rest of the connection
+    #[arg(long)]
+    title_per_dialog: bool,
+}
+
+/// OPTIONs mirrored into the environment, with their variable
+const ENV_OPTIONS: [(&str, &str); 4] = [
+    ("ttyname", "GPG_TTY"),
+    ("ttytype", "GPG_TERM"),
+    ("lc-ctype", "LC_CTYPE"),
+    ("lc-messages", "LC_MESSAGES"),
+];
+
+impl RofiArgs {
+    /// Environment variable an OPTION may set, if env mutation is enabled and allowed for it
+    fn env_var_for(&self, option: &str) -> Option<&'static str> {
+        if self.no_env_options || !self.env_options.iter().any(|allowed| allowed == option) {
+            return None;
+        }
+        ENV_OPTIONS
+            .iter()
+            .find(|(name, _)| *name == option)
+            .map(|(_, var)| *var)
+    }
+
+    /// `--log-level`, but logging warnings at least for `--redirect-stderr-to-log`
+    fn log_filter(&self) -> LevelFilter {
+        let level = LevelFilter::from(self.log_level);
+        if self.redirect_stderr_to_log {
+            level.max(LevelFilter::Warn)
+        } else {
+            level
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Matching {
+    Normal,
+    Fuzzy,
+    Regex,
+}
+
+impl Matching {
+    fn as_rofi_arg(&self) -> &'static str {
+        match self {
+            Matching::Normal => "normal",
+            Matching::Fuzzy => "fuzzy",
+            Matching::Regex => "regex",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PromptBidi {
+    Auto,
+    Ltr,
+    Rtl,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DescMarkup {
+    /// Everything shown as typed
+    Escape,
+    /// `<b>`, `<i>` and `<span foreground="…">` applied, everything else shown as typed
+    Safe,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum NewlineRepr {
+    /// Carriage return
+    Cr,
+    /// Line feed
+    Lf,
+    /// A visible `⏎` between the lines
+    Glyph,
+}
+
+impl NewlineRepr {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NewlineRepr::Cr => "\r",
+            NewlineRepr::Lf => "\n",
+            NewlineRepr::Glyph => " ⏎ ",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConfirmOnEmpty {
+    /// Operation cancelled
+    Cancel,
+    /// Not confirmed, as if Cancel was picked
+    NotConfirmed,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConfirmFocus {
+    /// Cancel, or OK when it is the only button
+    Safe,
+    /// OK
+    Affirmative,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AutoConfirm {
+    /// Confirmed, as if OK was picked
+    Yes,
+    /// Not confirmed, as if Cancel was picked. MESSAGE and `CONFIRM --one-button` have no
+    /// Cancel and are still acknowledged.
+    No,
+    /// Always show rofi
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+        }
+    }
+}
+
+/// Where the log goes: `--log-file`, or stderr
+fn log_sink(args: &RofiArgs) -> io::Result<Box<dyn Write + Send>> {
+    match &args.log_file {
+        Some(path) => Ok(Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(path)?,
+        )),
+        None => Ok(Box::new(io::stderr())),
+    }
+}
+
+/// `log` backend writing `pinentry-rofi: LEVEL: message` lines to `sink`
+struct Logger<W: Write + Send> {
+    level: LevelFilter,
+    sink: std::sync::Mutex<W>,
+}
+
+impl<W: Write + Send> Logger<W> {
+    fn new(sink: W, level: impl Into<LevelFilter>) -> Self {
+        Logger {
+            level: level.into(),
+            sink: std::sync::Mutex::new(sink),
+        }
+    }
+}
+
+impl<W: Write + Send> Log for Logger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = writeln!(sink, "pinentry-rofi: {}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = sink.flush();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum TimeoutSignal {
+    /// SIGTERM, then SIGKILL once the grace period is over
+    Term,
+    /// SIGKILL right away
+    Kill,
 }
 
 pub fn cmd() -> Command {
@@ -39,374 +562,2870 @@ pub fn cmd() -> Command {
     RofiArgs::augment_args(cli)
 }
 
-struct Writer<'a> {
-    writer: Box<dyn Write + 'a>,
+/// Per-connection state built up by the Assuan commands
+#[derive(Debug, Default)]
+struct State {
+    rofi_args: HashMap<String, Option<String>>,
+    /// Percent-decoded `OPTION` values by name
+    options: HashMap<String, String>,
+    /// From `SETTIMEOUT`. `None` waits for the user forever.
+    timeout: Option<Duration>,
+    /// Percent-decoded `SETTITLE`
+    title: Option<String>,
+    /// A dialog was asked for since `SETTITLE`, so `--title-per-dialog` may drop the title
+    title_shown: bool,
+    /// `-mesg` markup of the last `SETDESC`
+    desc: Option<String>,
+    /// `-mesg` markup of the last `SETERROR`, shown above the description
+    error: Option<String>,
+    /// "try N of M" from the last `SETERROR`
+    retry: Option<(u32, u32)>,
+    /// `SETPROMPT` asked for a smartcard PIN rather than a passphrase
+    pin_prompt: bool,
+    /// Keygrip, or cache id for a symmetric passphrase, from `SETKEYINFO`
+    keygrip: Option<String>,
+    /// What the `SETKEYINFO` cache mode prefix says the passphrase is for: `n/` and `s/` name a
+    /// key, `u/` a symmetric passphrase
+    keyinfo_context: Option<PassphraseContext>,
+    /// gpg-agent sent `SETREPEAT`, which it only does when asking for a new passphrase
+    new_passphrase: bool,
+    /// What the last `SETDESC` asked a passphrase for, if it could tell
+    desc_context: Option<PassphraseContext>,
+    /// Loaded from `--keygrip-map`
+    key_profiles: HashMap<String, KeyProfile>,
+    /// Time source for session deadlines
+    clock: Box<dyn Clock>,
+    /// Registered through `pinentry_with_observer`
+    observer: Option<Rc<dyn DialogObserver>>,
+    /// `GETINFO` replies worked out once per connection, so they stay the same if the
+    /// environment changes under us
+    info_replies: HashMap<&'static str, String>,
+}
+
+/// Time source, so tests can move time forward instead of waiting for it
+trait Clock: fmt::Debug {
+    fn now(&self) -> Instant;
 }
 
-impl<'a> Writer<'a> {
-    pub fn new() -> Self {
-        let writer = Box::new(std::io::stdout());
-        Writer { writer }
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
     }
 }
 
-impl Writer<'_> {
-    fn assuan_send(&mut self, mesg: &str) -> io::Result<()> {
-        writeln!(self.writer, "{}", mesg)?;
-        self.writer.flush()?;
-        Ok(())
+impl Default for Box<dyn Clock> {
+    fn default() -> Self {
+        Box::new(SystemClock)
     }
 }
 
-fn run_rofi(rofi_args: &HashMap<String, Option<String>>, writer: &mut Writer) -> io::Result<bool> {
-    let args: Vec<&String> = rofi_args
-        .iter()
-        .flat_map(|(arg, val)| match val {
-            Some(v) => vec![arg, v],
-            None => vec![arg],
-        })
-        .collect();
-    let output = process::Command::new("rofi").args(args).output()?;
-    if output.status.success() {
-        let pw = String::from_utf8(output.stdout)
-            .expect("Error reading rofi stdout")
-            .trim_end()
-            .to_owned();
-        if !pw.is_empty() {
-            writer.assuan_send(format!("D {}", pw).as_ref())?
+/// Prompt customization for one key
+#[derive(Clone, Debug, Default, PartialEq)]
+struct KeyProfile {
+    prompt: Option<String>,
+    theme_str: Option<String>,
+}
+
+/// What a passphrase is asked for, which decides the `--keygrip-map` section used without a
+/// section for the keygrip
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PassphraseContext {
+    /// Unlocking, importing or protecting a key
+    Key,
+    /// Symmetric encryption or decryption
+    Symmetric,
+}
+
+impl PassphraseContext {
+    /// `--keygrip-map` section, uppercased like keygrips
+    fn section(self) -> &'static str {
+        match self {
+            PassphraseContext::Key => "KEY",
+            PassphraseContext::Symmetric => "SYMMETRIC",
         }
+    }
+}
+
+/// Tell a key passphrase from a symmetric one by the wording of gpg's description
+fn passphrase_context(desc: &str) -> Option<PassphraseContext> {
+    let desc = desc.to_lowercase();
+    let mentions = |words: &[&str]| words.iter().any(|word| desc.contains(word));
+    if mentions(&["secret key", "private key", "new key", "unlock", "x.509"]) {
+        Some(PassphraseContext::Key)
+    } else if mentions(&["decryption", "encryption", "symmetric", "to decrypt", "to encrypt"]) {
+        Some(PassphraseContext::Symmetric)
     } else {
-        let mut err = String::from_utf8(output.stderr)
-            .expect("Error reading rofi stderr")
-            .to_owned();
-        if err.is_empty() {
-            err.push_str("rofi")
-        }
-        writer.assuan_send(format!("ERR 83886179 Operation cancelled <{err}>").as_ref())?;
-        return Ok(false);
+        None
     }
-    Ok(true)
 }
 
-#[derive(Debug)]
-struct UnknownAction {
-    action: String,
-    arg: String,
+/// Tell a key from a symmetric passphrase by the cache mode prefix of `SETKEYINFO`
+fn keyinfo_context(mode: &str) -> Option<PassphraseContext> {
+    match mode {
+        "n" | "s" => Some(PassphraseContext::Key),
+        "u" => Some(PassphraseContext::Symmetric),
+        _ => None,
+    }
 }
 
-impl fmt::Display for UnknownAction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Unknown assuan command. Action `{0}`. Argument `{1}`",
-            self.action, self.arg
-        )
+/// Parse the `--keygrip-map` file: `[KEYGRIP]` sections of `key = value` lines, `#` comments.
+fn parse_key_profiles(text: &str) -> Result<HashMap<String, KeyProfile>, String> {
+    let mut profiles = HashMap::new();
+    let mut current: Option<&mut KeyProfile> = None;
+    for (num, line) in text.lines().enumerate().map(|(num, line)| (num + 1, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(keygrip) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            current = Some(profiles.entry(keygrip.trim().to_uppercase()).or_default());
+            continue;
+        }
+        let Some(profile) = current.as_deref_mut() else {
+            return Err(format!("line {num}: expected a `[KEYGRIP]` section first"));
+        };
+        match line
+            .split_once('=')
+            .map(|(key, val)| (key.trim(), val.trim().to_owned()))
+        {
+            Some(("prompt", val)) => profile.prompt = Some(val),
+            Some(("theme-str", val)) => profile.theme_str = Some(val),
+            Some((key, _)) => return Err(format!("line {num}: unknown key `{key}`")),
+            None => return Err(format!("line {num}: expected `key = value`")),
+        }
     }
+    Ok(profiles)
 }
 
-impl error::Error for UnknownAction {}
+impl State {
+    /// Whether the answer must be digits only: `OPTION numeric-pin`, or a smartcard PIN prompt
+    /// with `--numeric-card-pin`
+    fn numeric_pin(&self, args: &RofiArgs) -> bool {
+        (args.numeric_card_pin && self.pin_prompt) || self.options.contains_key("numeric-pin")
+    }
 
-fn handle_command<'a>(
-    action: &'a str,
-    arg: &'a str,
-    rofi_args: &'a mut HashMap<String, Option<String>>,
-    writer: &'a mut Writer,
-    is_test: &'a bool,
-) -> BoxResult<()> {
-    let mut ok = true;
+    /// gpg hinted with `OPTION pinentry-mode=loopback` that the caller asks for the passphrase
+    /// itself, so no dialog may pop up
+    fn loopback(&self) -> bool {
+        self.options
+            .get("pinentry-mode")
+            .is_some_and(|mode| mode.eq_ignore_ascii_case("loopback"))
+    }
 
-    match (action, arg) {
-        ("OPTION", arg) => {
-            let (opt, val) = arg.split_once('=').unwrap_or_else(|| (&arg, ""));
-            match opt {
-                "ttyname" => env::set_var("GPG_TTY", val),
-                "ttytype" => env::set_var("GPG_TERM", val),
-                "lc-ctype" => env::set_var("LC_CTYPE", val),
-                "lc-messages" => env::set_var("LC_MESSAGES", val),
-                _ => {}
-            };
-        }
-        ("GETINFO", "pid") => writer.assuan_send(format!("D {}", process::id()).as_ref())?,
-        ("GETINFO", "ttyinfo") => writer.assuan_send(
-            format!(
-                "D {0} {1} {2}",
-                env::var("GPG_TTY")?,
-                env::var("GPG_TERM").unwrap_or("".into()),
-                env::var("DISPLAY")?
-            )
-            .as_ref(),
-        )?,
-        ("GETINFO", "flavor") => writer.assuan_send("D keyring")?,
-        ("GETINFO", "version") => writer.assuan_send(format!("D {}", env!("CARGO_PKG_VERSION")).as_ref())?,
-        ("SETPROMPT", arg) => {
-            if !rofi_args.contains_key("-p") {
-                rofi_args.insert("-p".to_owned(), Some(arg.replace(":", "")));
+    /// `-mesg` from the description and the error, whichever order they arrived in. A new
+    /// `SETERROR` replaces the previous one and a new `SETDESC` keeps it.
+    fn update_mesg(&mut self) {
+        let mesg = match (&self.error, &self.desc) {
+            (Some(error), Some(desc)) => format!("{error}\r***************************\r{desc}"),
+            (Some(mesg), None) | (None, Some(mesg)) => mesg.to_owned(),
+            (None, None) => {
+                self.rofi_args.remove("-mesg");
+                return;
             }
+        };
+        self.rofi_args.insert("-mesg".to_owned(), Some(mesg));
+    }
+
+    /// Forget the last `SETERROR` and its retry counter, keeping the description
+    fn clear_error(&mut self) {
+        self.error = None;
+        self.retry = None;
+        self.update_mesg();
+        self.update_window_title();
+    }
+
+    /// Locale from `OPTION lc-messages`, which picks the language of our `ERR` texts
+    fn lc_messages(&self) -> Option<&str> {
+        self.options.get("lc-messages").map(String::as_str)
+    }
+
+    /// State for another `--listen` connection: what came from the command line, none of the
+    /// Assuan commands
+    fn for_new_connection(&self) -> State {
+        State {
+            rofi_args: self.rofi_args.clone(),
+            key_profiles: self.key_profiles.clone(),
+            observer: self.observer.clone(),
+            ..Default::default()
         }
-        ("SETDESC", arg) => {
-            let unquoted = decode(arg).unwrap().into_owned().replace("\n", "\r");
-            let decoded = markup_escape_text(&unquoted);
-            rofi_args.insert("-mesg".to_owned(), Some(decoded.as_str().to_owned()));
+    }
+
+    /// Full passphrase rules to show on `--kb-constraints-help`, if the key is set and gpg sent them
+    fn constraints_help<'a>(&'a self, args: &RofiArgs) -> Option<&'a str> {
+        args.kb_constraints_help.as_ref()?;
+        self.options
+            .get("constraints-hint-long")
+            .map(String::as_str)
+            .filter(|hint| !hint.is_empty())
+    }
+
+    /// What the `SETKEYINFO` cache mode says, otherwise whatever the description says
+    fn passphrase_context(&self) -> Option<PassphraseContext> {
+        self.keyinfo_context.or(self.desc_context)
+    }
+
+    /// `--keygrip-map` profile for this prompt: the keygrip's own, or its context's
+    fn key_profile(&self) -> Option<&KeyProfile> {
+        self.keygrip
+            .as_ref()
+            .and_then(|keygrip| self.key_profiles.get(keygrip))
+            .or_else(|| self.key_profiles.get(self.passphrase_context()?.section()))
+    }
+
+    /// Forget the description and the error once a `MESSAGE` has shown them
+    fn clear_message(&mut self) {
+        self.desc = None;
+        self.desc_context = None;
+        self.clear_error();
+    }
+
+    /// Forget what the Assuan commands set up for the dialogs so far, as on `RESET`. `OPTION`s
+    /// and the command line stay.
+    fn reset(&mut self) {
+        self.timeout = None;
+        self.title = None;
+        self.title_shown = false;
+        self.pin_prompt = false;
+        self.keygrip = None;
+        self.keyinfo_context = None;
+        self.new_passphrase = false;
+        self.clear_message();
+    }
+
+    /// `--title-per-dialog`: a shown title is stale once the next dialog is about something else.
+    /// A retry repeats the key and the description, so it keeps the title.
+    fn drop_stale_title(&mut self, args: &RofiArgs, unrelated: bool) {
+        if args.title_per_dialog && self.title_shown && unrelated {
+            self.title = None;
+            self.title_shown = false;
+            self.update_window_title();
         }
-        ("GETPIN", _) => {
-            ok = if !is_test { run_rofi(rofi_args, writer)? } else { true };
+    }
+
+    /// `-window-title` from `SETTITLE`, with the retry counter appended
+    fn update_window_title(&mut self) {
+        let title = match (&self.title, self.retry) {
+            (Some(title), Some((try_n, of))) => format!("{title} (try {try_n} of {of})"),
+            (None, Some((try_n, of))) => format!("Try {try_n} of {of}"),
+            (Some(title), None) => title.to_owned(),
+            (None, None) => {
+                self.rofi_args.remove("-window-title");
+                return;
+            }
+        };
+        self.rofi_args.insert("-window-title".to_owned(), Some(title));
+    }
+
+    /// Value sent with `OPTION name`, falling back to the environment we were started with
+    fn option_or_env(&self, option: &str, var: &str) -> String {
+        match self.options.get(option) {
+            Some(val) => val.to_owned(),
+            None => env::var(var).unwrap_or_default(),
         }
-        ("SETERROR", arg) => {
-            let sep = "\r***************************\r";
-            rofi_args.entry("-mesg".to_owned()).and_modify(|e| {
-                let val = e.as_ref().unwrap();
-                let prev_msg = val.rsplit_once(sep).unwrap_or_else(|| ("", val)).1;
-                *e = Some([arg, prev_msg].join(sep))
-            });
+    }
+
+    /// The client's locale from `OPTION lc-ctype` and `lc-messages`, set for rofi alone whether
+    /// or not the OPTIONs may touch our own environment
+    fn rofi_env(&self) -> Vec<(&'static str, String)> {
+        [("lc-ctype", "LC_CTYPE"), ("lc-messages", "LC_MESSAGES")]
+            .into_iter()
+            .filter_map(|(option, var)| Some((var, self.options.get(option)?.to_owned())))
+            // A NUL can't go into the environment
+            .filter(|(_, val)| !val.contains('\0'))
+            .collect()
+    }
+
+    /// The `GETINFO what` reply, from `compute` the first time it is asked for
+    fn info_reply(&mut self, what: &'static str, compute: impl FnOnce(&Self) -> String) -> String {
+        if let Some(reply) = self.info_replies.get(what) {
+            return reply.clone();
         }
-        ("SETKEYINFO" | "BYE", _) => {}
-        _ => ok = false,
+        let reply = compute(self);
+        self.info_replies.insert(what, reply.clone());
+        reply
     }
 
-    if ok {
-        writer.assuan_send("OK")?
-    } else {
-        writer.assuan_send("BYE")?;
-        return Err(Box::new(UnknownAction {
-            action: action.to_owned(),
-            arg: arg.to_owned(),
-        }));
+    /// Button label from an `OPTION default-*` value without its `_` mnemonic marker
+    fn label(&self, option: &str, default: &str) -> String {
+        match self.options.get(option) {
+            Some(label) if !label.is_empty() => label.replace("__", "\0").replace('_', "").replace('\0', "_"),
+            _ => default.to_owned(),
+        }
     }
-    Ok(())
 }
 
-pub fn pinentry(args_matches: &ArgMatches) -> BoxResult<()> {
-    let args = RofiArgs::from_arg_matches(args_matches)
-        .map_err(|err| err.exit())
-        .unwrap();
+/// Assuan response channel over any write target.
+pub struct Writer<W: Write> {
+    writer: W,
+    flush_per_line: bool,
+}
 
-    let mut rofi_args = HashMap::from([
-        ("-dmenu".to_owned(), None),
-        ("-display".to_owned(), Some(args.display.to_owned())),
-        ("-input".to_owned(), Some("/dev/null".to_owned())),
-        ("-password".to_owned(), None),
-        ("-disable-history".to_owned(), None),
-        ("-l".to_owned(), Some("0".to_owned())),
-    ]);
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Writer {
+            writer,
+            flush_per_line: true,
+        }
+    }
 
-    match args.prompt {
-        Some(prompt) => {
-            if !prompt.is_empty() {
-                rofi_args.insert("-p".to_owned(), Some(prompt));
-            }
+    /// Leave flushing to the caller, who must `flush` before waiting for the next command
+    pub fn buffered(writer: W) -> Self {
+        Writer {
+            writer,
+            flush_per_line: false,
         }
-        None => {}
     }
 
-    let is_test = false;
-    let mut writer = Writer::new();
-    writer.assuan_send("OK Please go ahead")?;
-    for line in io::stdin().lock().lines() {
-        let cmd = line?;
-        let (action, arg) = cmd.split_once(' ').unwrap_or_else(|| (&cmd, ""));
-        handle_command(action, arg, &mut rofi_args, &mut writer, &is_test)?;
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, env, process};
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
 
-    struct AssuanEntry {
-        cmd: String,
-        etalon_output: String,
-        etalon_rofi_args: Option<HashMap<String, Option<String>>>,
+    pub fn assuan_send(&mut self, mesg: &str) -> io::Result<()> {
+        if log::log_enabled!(Level::Debug) {
+            let logged = if mesg.starts_with("D ") {
+                Cow::Borrowed("D [redacted]")
+            } else {
+                redact_secrets(mesg)
+            };
+            debug!("-> {logged}");
+        }
+        writeln!(self.writer, "{}", mesg)?;
+        if self.flush_per_line {
+            self.writer.flush()?;
+        }
+        Ok(())
     }
 
-    fn prepare_test_handle_command(
-        custom_prompt: Option<String>,
-    ) -> (HashMap<String, Option<String>>, Vec<AssuanEntry>) {
-        let mut assuan_mapping = vec![
-            AssuanEntry {
-                cmd: "OPTION grab".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION ttyname=/dev/pts/1".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION ttytype=tmux-256color".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION lc-messages=C".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION allow-external-password-cache".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-ok=_OK".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-cancel=_Cancel".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-yes=_Yes".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-no=_No".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-prompt=PIN:".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-pwmngr=_Save in password manager".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-cf-visi=Do you really want to make your \
-                                        passphrase visible on the screen?"
-                    .to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-tt-visi=Make passphrase visible".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION default-tt-hide=Hide passphrase".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "OPTION touch-file=/run/user/1000/gnupg/S.gpg-agent".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "GETINFO pid".to_owned(),
-                etalon_output: format!("D {}\nOK", process::id()),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "GETINFO ttyinfo".to_owned(),
-                etalon_output: (format!("D /dev/pts/1 tmux-256color {0}\nOK", env!("DISPLAY"))),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "GETINFO flavor".to_owned(),
-                etalon_output: "D keyring\nOK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "GETINFO version".to_owned(),
-                etalon_output: format!("D {}\nOK", env!("CARGO_PKG_VERSION")),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "SETPROMPT Passphrase:".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: Some(HashMap::from([
-                    ("-dmenu".to_owned(), None),
-                    ("-display".to_owned(), Some(":0".to_owned())),
-                    ("-input".to_owned(), Some("/dev/null".to_owned())),
-                    ("-password".to_owned(), None),
-                    ("-disable-history".to_owned(), None),
-                    ("-l".to_owned(), Some("0".to_owned())),
-                    ("-p".to_owned(), Some("Passphrase".to_owned())),
-                ])),
-            },
-            AssuanEntry {
-                cmd: "SETDESC Please enter the passphrase for the ssh key%0A  \
-                                ke:yf:in:ge:rp:ri:nt %22<email@yhoo.com>%22"
-                    .to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: Some(HashMap::from([
-                    ("-dmenu".to_owned(), None),
-                    ("-display".to_owned(), Some(":0".to_owned())),
-                    ("-input".to_owned(), Some("/dev/null".to_owned())),
-                    ("-password".to_owned(), None),
-                    ("-disable-history".to_owned(), None),
-                    ("-l".to_owned(), Some("0".to_owned())),
-                    ("-p".to_owned(), Some("Passphrase".to_owned())),
-                    (
-                        "-mesg".to_owned(),
-                        Some(
-                            "Please enter the passphrase for the ssh key\r  \
-                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                                .to_owned(),
-                        ),
-                    ),
-                ])),
-            },
-            AssuanEntry {
-                cmd: "GETPIN".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: Some(HashMap::from([
-                    ("-dmenu".to_owned(), None),
-                    ("-display".to_owned(), Some(":0".to_owned())),
-                    ("-input".to_owned(), Some("/dev/null".to_owned())),
-                    ("-password".to_owned(), None),
-                    ("-disable-history".to_owned(), None),
-                    ("-l".to_owned(), Some("0".to_owned())),
-                    ("-p".to_owned(), Some("Passphrase".to_owned())),
-                    (
-                        "-mesg".to_owned(),
-                        Some(
-                            "Please enter the passphrase for the ssh key\r  \
-                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                                .to_owned(),
-                        ),
-                    ),
-                ])),
-            },
-            AssuanEntry {
-                cmd: "SETERROR Bad Passphrase (try 2 of 3)".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: Some(HashMap::from([
-                    ("-dmenu".to_owned(), None),
-                    ("-display".to_owned(), Some(":0".to_owned())),
-                    ("-input".to_owned(), Some("/dev/null".to_owned())),
-                    ("-password".to_owned(), None),
-                    ("-disable-history".to_owned(), None),
-                    ("-l".to_owned(), Some("0".to_owned())),
-                    ("-p".to_owned(), Some("Passphrase".to_owned())),
-                    (
-                        "-mesg".to_owned(),
-                        Some(
-                            "Bad Passphrase (try 2 of 3)\r***************************\r\
-                             Please enter the passphrase for the ssh key\r  \
-                             ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                                .to_owned(),
-                        ),
+    /// Surface a non-fatal problem as an Assuan comment, which gpg-agent shows in its debug log
+    pub fn warn_to_client(&mut self, warning: &str) -> io::Result<()> {
+        self.assuan_send(&format!("# Warning: {}", warning.replace(['\r', '\n'], " ")))
+    }
+}
+
+impl Writer<Box<dyn Write>> {
+    pub fn stdout() -> Self {
+        Writer::new(Box::new(io::stdout()))
+    }
+}
+
+/// Writes that fail with `TimedOut` once `inner` has taken nothing for `timeout`. Each write
+/// waits for `poll` to report room and then hands over at most `PIPE_BUF` bytes, which a pipe
+/// with room takes without blocking. The fd stays blocking, as it may be shared with stdin.
+struct TimedWriter<W: Write + AsRawFd> {
+    inner: W,
+    timeout: Duration,
+}
+
+impl<W: Write + AsRawFd> TimedWriter<W> {
+    fn new(inner: W, timeout: Duration) -> Self {
+        TimedWriter { inner, timeout }
+    }
+
+    fn wait_writable(&self) -> io::Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.inner.as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+            let left = deadline.saturating_duration_since(Instant::now());
+            // SAFETY: one valid pollfd, polled for at most `left`.
+            match unsafe { libc::poll(&mut pollfd, 1, left.as_millis().try_into().unwrap_or(i32::MAX)) } {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Assuan output blocked for over {:?}", self.timeout),
+                    ))
+                }
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<W: Write + AsRawFd> Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wait_writable()?;
+        self.inner.write(&buf[..buf.len().min(libc::PIPE_BUF)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn run_hook(name: &str, hook: &Option<String>) {
+    let Some(hook) = hook else { return };
+    match process::Command::new("sh").arg("-c").arg(hook).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!("{name} `{hook}` failed: {status}"),
+        Err(err) => error!("{name} `{hook}` failed: {err}"),
+    }
+}
+
+/// Start the `--notify` command without waiting for it, so a slow one never holds up the prompt.
+/// The returned thread reaps it and logs a failure.
+fn notify(command: &Option<String>) -> Option<thread::JoinHandle<()>> {
+    let command = command.clone()?;
+    let child = process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn();
+    Some(thread::spawn(move || match child.and_then(|mut child| child.wait()) {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!("notify `{command}` failed: {status}"),
+        Err(err) => error!("notify `{command}` failed: {err}"),
+    }))
+}
+
+/// Run a dialog between the hooks, tracing how long each part took
+fn with_hooks<T>(args: &RofiArgs, prompt: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    run_hook("pre-cmd", &args.pre_cmd);
+    let shown = Instant::now();
+    let result = prompt();
+    let answered = Instant::now();
+    run_hook("post-cmd", &args.post_cmd);
+    debug!(
+        "Dialog timing: pre-cmd {:?}, rofi {:?}, post-cmd {:?}",
+        shown - started,
+        answered - shown,
+        answered.elapsed()
+    );
+    result
+}
+
+/// Spawns rofi with `env` added to its environment. Tests swap it for a runner with canned
+/// outputs. Runs past `timeout` fail with `io::ErrorKind::TimedOut`.
+trait Rofi {
+    fn run(
+        &mut self,
+        args: &[String],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+        env: &[(&'static str, String)],
+    ) -> io::Result<process::Output>;
+
+    /// Whether rofi stands a chance on `display`, checked before spawning it
+    fn display_available(&self, display: &str) -> bool {
+        display_available(display, env::var("WAYLAND_DISPLAY").ok().as_deref())
+    }
+
+    /// Why the passphrase may show in plain text, sent to the client before each `GETPIN`
+    fn masking_warning(&mut self) -> Option<String> {
+        None
+    }
+}
+
+struct RofiProcess {
+    program: String,
+    timeout_signal: TimeoutSignal,
+    grace: Duration,
+    max_pin_len: Option<usize>,
+    /// `--end-marker`, which rofi prints after the passphrase
+    end_marker: Option<String>,
+    stderr_to_log: bool,
+    /// From `rofi -version`, asked at the first `GETPIN` only
+    masking_warning: std::cell::OnceCell<Option<String>>,
+}
+
+impl RofiProcess {
+    fn new(args: &RofiArgs) -> Self {
+        RofiProcess {
+            program: args.rofi_bin.to_owned(),
+            timeout_signal: args.rofi_timeout_signal,
+            grace: Duration::from_millis(args.rofi_grace_ms),
+            max_pin_len: args.max_pin_len,
+            end_marker: args.end_marker.clone(),
+            stderr_to_log: args.redirect_stderr_to_log,
+            masking_warning: std::cell::OnceCell::new(),
+        }
+    }
+}
+
+/// What the timeout path needs from a running rofi, so tests can fake the process and the clock
+trait Terminable {
+    fn signal(&mut self, signal: libc::c_int) -> io::Result<()>;
+    fn exited(&mut self) -> io::Result<bool>;
+}
+
+impl Terminable for process::Child {
+    fn signal(&mut self, signal: libc::c_int) -> io::Result<()> {
+        // SAFETY: plain syscall on a child we haven't reaped yet.
+        if unsafe { libc::kill(self.id() as libc::pid_t, signal) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn exited(&mut self) -> io::Result<bool> {
+        Ok(self.try_wait()?.is_some())
+    }
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Poll until the process exits or `limit` has been slept away. Returns whether it exited.
+fn wait_for_exit(child: &mut impl Terminable, limit: Duration, sleep: &mut impl FnMut(Duration)) -> io::Result<bool> {
+    let mut waited = Duration::ZERO;
+    while !child.exited()? {
+        if waited >= limit {
+            return Ok(false);
+        }
+        let step = POLL_INTERVAL.min(limit - waited);
+        sleep(step);
+        waited += step;
+    }
+    Ok(true)
+}
+
+/// Stop a timed out rofi: SIGTERM and up to `grace` to exit before SIGKILL, or SIGKILL at once.
+fn terminate(
+    child: &mut impl Terminable,
+    signal: TimeoutSignal,
+    grace: Duration,
+    mut sleep: impl FnMut(Duration),
+) -> io::Result<()> {
+    if signal == TimeoutSignal::Term {
+        child.signal(libc::SIGTERM)?;
+        if wait_for_exit(child, grace, &mut sleep)? {
+            return Ok(());
+        }
+    }
+    child.signal(libc::SIGKILL)
+}
+
+/// Configure the rofi process: its own process group, so signals aimed at our group (and the
+/// terminal) don't reach it, and a SIGTERM when we die, so it never outlives us.
+fn rofi_command<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    has_input: bool,
+    env: &[(&'static str, String)],
+) -> process::Command {
+    let mut command = process::Command::new(program);
+    command
+        .args(args)
+        .envs(env.iter().map(|(var, val)| (var, val)))
+        .stdin(if has_input { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Linux only. Elsewhere rofi outlives a killed pinentry-rofi, as it did before.
+            #[cfg(target_os = "linux")]
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            Ok(())
+        });
+    }
+    command
+}
+
+impl Rofi for RofiProcess {
+    fn run(
+        &mut self,
+        args: &[String],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+        env: &[(&'static str, String)],
+    ) -> io::Result<process::Output> {
+        let output = self.run_child(args, input, timeout, env)?;
+        if self.stderr_to_log {
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                warn!("rofi: {line}");
+            }
+        }
+        Ok(output)
+    }
+
+    fn masking_warning(&mut self) -> Option<String> {
+        self.masking_warning
+            .get_or_init(|| masking_warning(rofi_version(&self.program, PROBE_TIMEOUT).as_deref()))
+            .clone()
+    }
+}
+
+impl RofiProcess {
+    fn run_child(
+        &self,
+        args: &[String],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+        env: &[(&'static str, String)],
+    ) -> io::Result<process::Output> {
+        let started = Instant::now();
+        let mut child = rofi_command(&self.program, args, input.is_some(), env).spawn()?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            if let Err(err) = stdin.write_all(input.as_bytes()) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        }
+        debug!("rofi started in {:?}", started.elapsed());
+        if let Some(timeout) = timeout {
+            if !wait_for_exit(&mut child, timeout, &mut thread::sleep)? {
+                terminate(&mut child, self.timeout_signal, self.grace, thread::sleep)?;
+                child.wait()?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "rofi timed out"));
+            }
+        }
+        // Runs without input are passphrase prompts, the others only echo one of our entries
+        let (Some(max_pin_len), None) = (self.max_pin_len, input) else {
+            return child.wait_with_output();
+        };
+        let Some(mut stdout) = child.stdout.take() else {
+            return child.wait_with_output();
+        };
+        // Drained alongside stdout, or rofi blocks once it fills the stderr pipe
+        let stderr = child.stderr.take().map(|mut err| {
+            thread::spawn(move || {
+                let mut stderr = Vec::new();
+                err.read_to_end(&mut stderr).map(|_| stderr)
+            })
+        });
+        // Room for the marker and line end, which don't count towards the limit
+        let marker = self.end_marker.as_deref().unwrap_or_default();
+        let limit = max_pin_len + marker.len() + "\r\n".len();
+        let mut pin = match read_bounded(&mut stdout, limit) {
+            Ok(pin) if passphrase_len(&pin, marker) <= max_pin_len => pin,
+            result => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(match result {
+                    Err(err) if err.kind() != io::ErrorKind::InvalidData => err,
+                    _ => io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the passphrase is longer than {max_pin_len} bytes"),
                     ),
-                ])),
-            },
-            AssuanEntry {
-                cmd: "SETKEYINFO".to_owned(),
-                etalon_output: "OK".to_owned(),
-                etalon_rofi_args: None,
-            },
-            AssuanEntry {
-                cmd: "BYE".to_owned(),
-                etalon_output: "OK".to_owned(),
+                });
+            }
+        };
+        let stderr = match stderr {
+            Some(reader) => reader
+                .join()
+                .map_err(|_| io::Error::other("rofi stderr reader panicked"))??,
+            None => Vec::new(),
+        };
+        Ok(process::Output {
+            status: child.wait()?,
+            stdout: std::mem::take(&mut *pin),
+            stderr,
+        })
+    }
+}
+
+/// Length of the passphrase in rofi's `stdout`, without the line end and the `marker` after it
+fn passphrase_len(stdout: &[u8], marker: &str) -> usize {
+    let line = stdout
+        .iter()
+        .rposition(|&b| b != b'\n' && b != b'\r')
+        .map_or(&stdout[..0], |last| &stdout[..=last]);
+    line.strip_suffix(marker.as_bytes()).unwrap_or(line).len()
+}
+
+/// Read at most `limit` bytes into a buffer allocated once up front, so no reallocation leaves a
+/// copy of the secret behind. Fails with `InvalidData` as soon as there is more.
+fn read_bounded(reader: &mut impl Read, limit: usize) -> io::Result<Zeroizing<Vec<u8>>> {
+    let mut buf = Zeroizing::new(vec![0; limit + 1]);
+    let mut len = 0;
+    loop {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) if len + n > limit => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("rofi output is longer than {limit} bytes"),
+                ))
+            }
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// The full rofi command line for a dialog: its arguments sorted by name, each followed by its
+/// value, then the flag-driven ones, the colors and a `-theme-str` for each `--theme-str`
+fn build_rofi_command(rofi_args: &HashMap<String, Option<String>>, args: &RofiArgs) -> Vec<String> {
+    let mut names: Vec<_> = rofi_args.keys().collect();
+    names.sort();
+    let mut command = Vec::new();
+    for name in names {
+        command.push(name.to_owned());
+        if let Some(value) = &rofi_args[name] {
+            command.push(match args.prompt_bidi {
+                Some(bidi) if name == "-p" || name == "-mesg" => bidi_marked(value, bidi),
+                _ => value.to_owned(),
+            });
+        }
+    }
+    command.extend(
+        args.foreground
+            .then_some("-steal-focus")
+            .into_iter()
+            .chain(args.ime_friendly.then_some("-normal-window"))
+            .map(str::to_owned),
+    );
+    if let Some(theme) = color_theme(args) {
+        command.extend(["-theme-str".to_owned(), theme]);
+    }
+    if let Some(width) = args.max_mesg_width {
+        // The message textbox wraps at the window width
+        command.extend(["-theme-str".to_owned(), format!("window {{ width: {width}ch; }}")]);
+    }
+    command.extend(
+        args.theme_str
+            .iter()
+            .flat_map(|theme| ["-theme-str", theme.as_str()])
+            .map(str::to_owned),
+    );
+    command
+}
+
+/// Letters of the right-to-left scripts: Hebrew, Arabic, Syriac, Thaana, N'Ko and their
+/// presentation forms, plus the historic ones in the supplementary planes
+fn is_rtl(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0590}'..='\u{08FF}'
+            | '\u{FB1D}'..='\u{FDFF}'
+            | '\u{FE70}'..='\u{FEFF}'
+            | '\u{10800}'..='\u{10FFF}'
+            | '\u{1E800}'..='\u{1EFFF}'
+    )
+}
+
+/// Whether the first letter of rofi markup, ignoring tags and entities, is right-to-left
+fn first_letter_rtl(markup: &str) -> Option<bool> {
+    let mut skip_until = None;
+    for c in markup.chars() {
+        match (skip_until, c) {
+            (Some(end), c) if c == end => skip_until = None,
+            (Some(_), _) => {}
+            (None, '<') => skip_until = Some('>'),
+            (None, '&') => skip_until = Some(';'),
+            (None, c) if c.is_alphabetic() => return Some(is_rtl(c)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Start `text` and every line in it with a left-to-right or right-to-left mark. Pango takes
+/// each paragraph's direction from its first strong character, so the mark decides it.
+fn bidi_marked(text: &str, bidi: PromptBidi) -> String {
+    let rtl = match bidi {
+        PromptBidi::Ltr => false,
+        PromptBidi::Rtl => true,
+        PromptBidi::Auto => match first_letter_rtl(text) {
+            Some(rtl) => rtl,
+            None => return text.to_owned(),
+        },
+    };
+    let mark = if rtl { '\u{200F}' } else { '\u{200E}' };
+    let mut marked = String::from(mark);
+    for c in text.chars() {
+        marked.push(c);
+        if c == '\r' || c == '\n' {
+            marked.push(mark);
+        }
+    }
+    marked
+}
+
+/// The first line of rofi's stderr reporting a broken `-theme`/`-theme-str`, without its markup
+fn theme_error(stderr: &str) -> Option<String> {
+    stderr.lines().find_map(|line| {
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in line.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' if in_tag => in_tag = false,
+                c if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        let lower = text.to_lowercase();
+        let broken = ["error", "failed", "parse"].iter().any(|word| lower.contains(word));
+        (lower.contains("theme") && broken).then(|| text.trim().to_owned())
+    })
+}
+
+/// How a rofi prompt ended
+#[derive(Debug)]
+enum RofiOutcome {
+    /// Accepted text, without the trailing newline
+    Pin(Zeroizing<String>),
+    /// Accepted with nothing entered
+    Empty,
+    /// Dismissed by the user
+    Cancelled,
+    /// One of the `-kb-custom-N` bindings was pressed
+    Keybind(u8),
+    /// rofi failed, with whatever it had to say about it
+    Error(String),
+    /// rofi crashed or was killed by this signal. Not the user's doing, unlike a cancel.
+    Killed(i32),
+}
+
+impl From<process::Output> for RofiOutcome {
+    fn from(output: process::Output) -> Self {
+        if let Some(signal) = output.status.signal() {
+            // Crashed or killed, not dismissed: the exit code and stderr say nothing useful
+            error!("rofi terminated by signal {signal}");
+            return RofiOutcome::Killed(signal);
+        }
+        if !output.status.success() {
+            if let Some(err) = theme_error(&String::from_utf8_lossy(&output.stderr)) {
+                // rofi gave up on the theme; say so rather than pass its exit code off as a cancel
+                return RofiOutcome::Error(format!("rofi theme error: {err}"));
+            }
+            let err = String::from_utf8_lossy(&output.stderr).trim_end().to_owned();
+            return match (output.status.code(), err.is_empty()) {
+                (Some(1), true) => RofiOutcome::Cancelled,
+                (Some(code @ 10..=28), _) => RofiOutcome::Keybind(code as u8 - 9),
+                (_, true) => RofiOutcome::Error(output.status.to_string()),
+                (_, false) => RofiOutcome::Error(err),
+            };
+        }
+        match String::from_utf8(output.stdout) {
+            Ok(pin) => {
+                let mut pin = Zeroizing::new(pin);
+                let len = pin.trim_end_matches(['\n', '\r']).len();
+                pin.truncate(len);
+                if pin.is_empty() {
+                    RofiOutcome::Empty
+                } else {
+                    RofiOutcome::Pin(pin)
+                }
+            }
+            Err(err) => {
+                drop(Zeroizing::new(err.into_bytes()));
+                RofiOutcome::Error("rofi output is not valid UTF-8".to_owned())
+            }
+        }
+    }
+}
+
+/// A dialog showing up or ending, as told to a [`DialogObserver`]. Never carries the passphrase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DialogOutcome {
+    /// rofi is about to show a `GETPIN` or `CONFIRM` dialog
+    Shown,
+    /// `GETPIN` was answered, possibly with an empty passphrase
+    PinEntered,
+    /// `CONFIRM` was answered with OK
+    Confirmed,
+    /// `CONFIRM` was answered with Cancel
+    NotConfirmed,
+    /// The dialog was closed without an answer
+    Cancelled,
+    /// rofi failed, timed out or couldn't be shown
+    Error,
+}
+
+/// Told about each dialog, for programs embedding pinentry-rofi. Closures taking a
+/// [`DialogOutcome`] are observers too.
+pub trait DialogObserver {
+    fn dialog_outcome(&self, outcome: DialogOutcome);
+}
+
+impl<F: Fn(DialogOutcome)> DialogObserver for F {
+    fn dialog_outcome(&self, outcome: DialogOutcome) {
+        self(outcome)
+    }
+}
+
+impl fmt::Debug for dyn DialogObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DialogObserver")
+    }
+}
+
+/// Tell the observer how a dialog went and record it in the `--output-file`. Failing to write
+/// the file is logged, not fatal.
+fn report_outcome(state: &State, args: &RofiArgs, outcome: DialogOutcome) {
+    if let Some(observer) = &state.observer {
+        observer.dialog_outcome(outcome);
+    }
+    let status = match outcome {
+        DialogOutcome::Shown => return,
+        DialogOutcome::PinEntered | DialogOutcome::Confirmed => "ok",
+        DialogOutcome::NotConfirmed | DialogOutcome::Cancelled => "cancel",
+        DialogOutcome::Error => "error",
+    };
+    let Some(path) = &args.output_file else { return };
+    if let Err(err) = std::fs::write(path, format!("{status}\n")) {
+        error!("--output-file `{}`: {err}", path.display());
+    }
+}
+
+/// `ERR` texts that have translations
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    Cancelled,
+    Timeout,
+    RofiNotFound,
+}
+
+/// `message` in the language of an `LC_MESSAGES` locale like `de_DE.UTF-8`, English if there is
+/// no translation
+fn localized(message: Message, lc_messages: Option<&str>) -> &'static str {
+    let language = lc_messages.and_then(|locale| locale.split(['_', '.', '@']).next());
+    match (language, message) {
+        (Some("de"), Message::Cancelled) => "Vorgang abgebrochen",
+        (Some("de"), Message::Timeout) => "Zeitüberschreitung",
+        (Some("de"), Message::RofiNotFound) => "rofi nicht gefunden",
+        (Some("es"), Message::Cancelled) => "Operación cancelada",
+        (Some("es"), Message::Timeout) => "Tiempo de espera agotado",
+        (Some("es"), Message::RofiNotFound) => "rofi no encontrado",
+        (Some("fr"), Message::Cancelled) => "Opération annulée",
+        (Some("fr"), Message::Timeout) => "Délai dépassé",
+        (Some("fr"), Message::RofiNotFound) => "rofi introuvable",
+        (Some("ru"), Message::Cancelled) => "Операция отменена",
+        (Some("ru"), Message::Timeout) => "Время ожидания истекло",
+        (Some("ru"), Message::RofiNotFound) => "rofi не найден",
+        (_, Message::Cancelled) => "Operation cancelled",
+        (_, Message::Timeout) => "Timeout",
+        (_, Message::RofiNotFound) => "rofi not found",
+    }
+}
+
+fn send_cancelled(detail: &str, lc_messages: Option<&str>, writer: &mut Writer<impl Write>) -> io::Result<()> {
+    writer.assuan_send(
+        format!(
+            "ERR {} {} <{}>",
+            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_CANCELED),
+            localized(Message::Cancelled, lc_messages),
+            if detail.is_empty() { "rofi" } else { detail }
+        )
+        .as_ref(),
+    )
+}
+
+/// `ERR` for rofi not being there to ask with, which no retry or Escape would change
+fn send_no_pinentry(detail: &str, writer: &mut Writer<impl Write>) -> io::Result<()> {
+    writer.assuan_send(
+        format!(
+            "ERR {} No pinentry <{detail}>",
+            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_NO_PIN_ENTRY)
+        )
+        .as_ref(),
+    )
+}
+
+/// `ERR` for rofi failing on its own, which gpg-agent must not take for the user cancelling
+fn send_failed(detail: &str, writer: &mut Writer<impl Write>) -> io::Result<()> {
+    writer.assuan_send(
+        format!(
+            "ERR {} General error <{detail}>",
+            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_GENERAL)
+        )
+        .as_ref(),
+    )
+}
+
+fn send_invalid_argument(action: &str, arg: &str, writer: &mut Writer<impl Write>) -> io::Result<()> {
+    writer.assuan_send(
+        format!(
+            "ERR {} Invalid {action} argument `{arg}`",
+            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_ASS_PARAMETER)
+        )
+        .as_ref(),
+    )
+}
+
+fn send_timeout(lc_messages: Option<&str>, writer: &mut Writer<impl Write>) -> io::Result<()> {
+    writer.assuan_send(
+        format!(
+            "ERR {} {}",
+            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_TIMEOUT),
+            localized(Message::Timeout, lc_messages)
+        )
+        .as_ref(),
+    )
+}
+
+/// Confirmation text pinentry shows before unmasking when no `default-cf-visi` was given
+const DEFAULT_CF_VISI: &str = "Do you really want to make your passphrase visible on the screen?";
+
+/// Whether the passphrase prompt masks what is typed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PinVisibility {
+    Hidden,
+    Visible,
+}
+
+impl PinVisibility {
+    /// State after the toggle key was pressed. Unmasking needs `confirm` to agree, hiding doesn't.
+    fn toggled(self, confirm: impl FnOnce() -> io::Result<bool>) -> io::Result<Self> {
+        Ok(match self {
+            PinVisibility::Hidden if confirm()? => PinVisibility::Visible,
+            PinVisibility::Hidden => PinVisibility::Hidden,
+            PinVisibility::Visible => PinVisibility::Hidden,
+        })
+    }
+
+    /// Hint for the toggle key, from the `default-tt-visi`/`default-tt-hide` tooltips
+    fn hint(self, state: &State) -> &str {
+        let (option, default) = match self {
+            PinVisibility::Hidden => ("default-tt-visi", "Make passphrase visible"),
+            PinVisibility::Visible => ("default-tt-hide", "Hide passphrase"),
+        };
+        state
+            .options
+            .get(option)
+            .map(String::as_str)
+            .filter(|hint| !hint.is_empty())
+            .unwrap_or(default)
+    }
+}
+
+/// Arguments of the passphrase prompt. `notice` is shown above the message, e.g. why the last
+/// answer was rejected.
+fn pin_rofi_args(
+    state: &State,
+    args: &RofiArgs,
+    visibility: PinVisibility,
+    notice: Option<&str>,
+) -> HashMap<String, Option<String>> {
+    let mut pin_args = state.rofi_args.clone();
+    let profile = state.key_profile();
+    if let Some(prompt) = profile.and_then(|profile| profile.prompt.as_ref()) {
+        pin_args.insert("-p".to_owned(), Some(prompt.to_owned()));
+    }
+    if let Some(template) = &args.prompt_template {
+        let values = HashMap::from([
+            ("prompt", pin_args.get("-p").cloned().flatten().unwrap_or_default()),
+            ("host", hostname()),
+            ("user", env::var("USER").unwrap_or_default()),
+            ("keygrip", state.keygrip.clone().unwrap_or_default()),
+        ]);
+        pin_args.insert("-p".to_owned(), Some(render_template(template, &values)));
+    }
+    if let Some(key) = &args.kb_show_passphrase {
+        pin_args.insert("-kb-custom-1".to_owned(), Some(key.to_owned()));
+        let hint = escape_mesg(&format!("{key}: {}", visibility.hint(state)));
+        let mesg = match pin_args.remove("-mesg").flatten() {
+            Some(mesg) => format!("{mesg}\r{hint}"),
+            None => hint,
+        };
+        pin_args.insert("-mesg".to_owned(), Some(mesg));
+    }
+    let mut hints: Vec<String> = state
+        .options
+        .get("constraints-hint-short")
+        .filter(|hint| !hint.is_empty())
+        .cloned()
+        .into_iter()
+        .collect();
+    if let (Some(key), Some(_)) = (&args.kb_constraints_help, state.constraints_help(args)) {
+        pin_args.insert("-kb-custom-2".to_owned(), Some(key.to_owned()));
+        hints.push(format!("{key}: Passphrase rules"));
+    }
+    if !hints.is_empty() {
+        let hints = escape_mesg(&hints.join("\n"));
+        let mesg = match pin_args.remove("-mesg").flatten() {
+            Some(mesg) => format!("{mesg}\r{hints}"),
+            None => hints,
+        };
+        pin_args.insert("-mesg".to_owned(), Some(mesg));
+    }
+    let mut notices: Vec<String> = notice.map(str::to_owned).into_iter().collect();
+    if let (true, Some((try_n, of))) = (args.show_attempts, state.retry) {
+        notices.push(attempts_glyphs(try_n, of));
+    }
+    if !notices.is_empty() {
+        let notice = escape_mesg(&notices.join("\n"));
+        let mesg = match pin_args.remove("-mesg").flatten() {
+            Some(mesg) => format!("{notice}\r{mesg}"),
+            None => notice,
+        };
+        pin_args.insert("-mesg".to_owned(), Some(mesg));
+    }
+    if visibility == PinVisibility::Visible {
+        pin_args.remove("-password");
+    }
+    if args.prompt_as_password_placeholder {
+        // Without `-p` rofi labels the entry "dmenu", so the label is blanked rather than dropped
+        if let Some(prompt) = pin_args.insert("-p".to_owned(), Some(String::new())).flatten() {
+            let theme = format!("entry {{ placeholder: {}; }}", rasi_string(&prompt));
+            pin_args.insert("-theme-str".to_owned(), Some(theme));
+        }
+    }
+    if let Some(placeholder) = &args.entry_placeholder {
+        let theme = format!("entry {{ placeholder: {}; }}", rasi_string(placeholder));
+        pin_args.insert("-theme-str".to_owned(), Some(theme));
+    }
+    if let Some(theme) = profile.and_then(|profile| profile.theme_str.as_ref()) {
+        pin_args
+            .entry("-theme-str".to_owned())
+            .and_modify(|generated| *generated = generated.take().map(|generated| format!("{generated} {theme}")))
+            .or_insert_with(|| Some(theme.to_owned()));
+    }
+    if let Some(marker) = &args.end_marker {
+        pin_args.insert("-format".to_owned(), Some(format!("s{marker}")));
+    }
+    apply_newline_repr(&mut pin_args, args);
+    pin_args
+}
+
+/// Make a prompt from the environment safe to show: control characters become spaces and runs
+/// of whitespace collapse into one
+fn sanitize_prompt(prompt: &str) -> String {
+    prompt
+        .split(|c: char| c.is_control() || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Messages are built with `\r` between lines. Swap in the `--newline-repr` separator.
+fn apply_newline_repr(rofi_args: &mut HashMap<String, Option<String>>, args: &RofiArgs) {
+    if let Some(Some(mesg)) = rofi_args.get_mut("-mesg") {
+        *mesg = mesg.replace('\r', args.newline_repr.as_str());
+    }
+}
+
+/// scdaemon prompts for card PINs with `PIN`, `Admin PIN` or `Reset Code` (`||` marks its
+/// extended prompt text)
+fn is_pin_prompt(prompt: &str) -> bool {
+    let prompt = prompt.trim_start_matches('|').trim().trim_end_matches(':');
+    ["PIN", "Admin PIN", "Reset Code"].contains(&prompt)
+}
+
+/// Replace `{name}` placeholders with their value. Unknown ones are left as they are.
+fn render_template(template: &str, values: &HashMap<&str, String>) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else { break };
+        if rest[1..end].contains('{') {
+            // `{` without its own `}`: keep it and look for a placeholder after it
+            rendered.push('{');
+            rest = &rest[1..];
+            continue;
+        }
+        match values.get(&rest[1..end]) {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The marker goes into rofi's `-format`, where these letters would be replaced by rofi
+fn parse_end_marker(marker: &str) -> Result<String, String> {
+    if marker.is_empty() || marker.contains(['s', 'i', 'd', 'q', 'f', 'F', 'p', '\n']) {
+        return Err("must be non-empty and free of the -format letters s, i, d, q, f, F, p and newlines".to_owned());
+    }
+    Ok(marker.to_owned())
+}
+
+/// Colors go into a `-theme-str` as is, so only hex codes and plain names get through
+fn parse_color(color: &str) -> Result<String, String> {
+    let valid = match color.strip_prefix('#') {
+        Some(hex) => [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
+    };
+    if !valid {
+        return Err("must be #rgb, #rgba, #rrggbb, #rrggbbaa or a color name".to_owned());
+    }
+    Ok(color.to_owned())
+}
+
+/// `-theme-str` for `--prompt-color` and `--mesg-color`
+fn color_theme(args: &RofiArgs) -> Option<String> {
+    let rules: Vec<_> = [("prompt", &args.prompt_color), ("textbox", &args.mesg_color)]
+        .into_iter()
+        .filter_map(|(widget, color)| Some(format!("{widget} {{ text-color: {}; }}", color.as_ref()?)))
+        .collect();
+    (!rules.is_empty()).then(|| rules.join(" "))
+}
+
+/// Keep what precedes the `--end-marker`. Output without it was cut short; output with it twice
+/// has it inside the passphrase, which can't be told apart.
+fn strip_end_marker(stdout: &[u8], marker: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let marker = marker.as_bytes();
+    let mut found = (0..stdout.len()).filter(|&pos| stdout[pos..].starts_with(marker));
+    let (Some(pos), None) = (found.next(), found.next()) else {
+        return Err(match stdout.windows(marker.len()).any(|window| window == marker) {
+            true => "the passphrase contains the end marker".to_owned(),
+            false => "rofi output ended before the end marker".to_owned(),
+        });
+    };
+    if !stdout[pos + marker.len()..].iter().all(u8::is_ascii_whitespace) {
+        return Err("rofi output continues after the end marker".to_owned());
+    }
+    Ok(Zeroizing::new(stdout[..pos].to_vec()))
+}
+
+/// "try N of M" as M circles, the first N filled: `●●○` for try 2 of 3
+fn attempts_glyphs(try_n: u32, of: u32) -> String {
+    let of = of.min(20);
+    let used = try_n.min(of);
+    "●".repeat(used as usize) + &"○".repeat((of - used) as usize)
+}
+
+/// Quote `text` as a rasi string for `-theme-str`
+fn rasi_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Ask for the passphrase. The `--kb-show-passphrase` binding re-launches the prompt with the
+/// other visibility; whatever was typed so far is dropped rather than pre-filled. A smartcard
+/// PIN with anything but digits is asked again.
+fn run_rofi(state: &State, args: &RofiArgs, rofi: &mut dyn Rofi) -> io::Result<RofiOutcome> {
+    let mut visibility = PinVisibility::Hidden;
+    let mut notice = None;
+    let min_len = args.password_min_length.filter(|_| state.new_passphrase).unwrap_or(0);
+    let bad_code = |pin: &str| {
+        args.numeric_len
+            .is_some_and(|len| pin.len() != len || !pin.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let code_notice = args.numeric_len.map(|len| format!("Enter exactly {len} digits"));
+    let length_notice = format!("The passphrase must be at least {min_len} characters long");
+    loop {
+        let pin_args = pin_rofi_args(state, args, visibility, notice.as_deref());
+        let mut output = with_hooks(args, || {
+            rofi.run(
+                &build_rofi_command(&pin_args, args),
+                None,
+                state.timeout,
+                &state.rofi_env(),
+            )
+        })?;
+        if let (Some(marker), true) = (&args.end_marker, output.status.success()) {
+            let raw = Zeroizing::new(std::mem::take(&mut output.stdout));
+            match strip_end_marker(&raw, marker) {
+                Ok(mut stdout) => output.stdout = std::mem::take(&mut *stdout),
+                Err(err) => return Ok(RofiOutcome::Error(err)),
+            }
+        }
+        match RofiOutcome::from(output) {
+            RofiOutcome::Keybind(1) if args.kb_show_passphrase.is_some() => {
+                visibility = visibility.toggled(|| {
+                    let question = state
+                        .options
+                        .get("default-cf-visi")
+                        .map_or(DEFAULT_CF_VISI, String::as_str);
+                    Ok(confirm_dialog(state, args, Some(escape_mesg(question)), false, rofi)?
+                        == Confirmation::Confirmed)
+                })?;
+            }
+            RofiOutcome::Keybind(2) if state.constraints_help(args).is_some() => {
+                let rules = state.constraints_help(args).map(escape_mesg);
+                confirm_dialog(state, args, rules, true, rofi)?;
+            }
+            RofiOutcome::Pin(pin) if state.numeric_pin(args) && !pin.chars().all(|c| c.is_ascii_digit()) => {
+                notice = Some("The PIN may only contain digits".to_owned());
+            }
+            RofiOutcome::Pin(pin) if bad_code(&pin) => notice = code_notice.clone(),
+            RofiOutcome::Empty if bad_code("") => notice = code_notice.clone(),
+            RofiOutcome::Pin(pin) if pin.chars().count() < min_len => notice = Some(length_notice.clone()),
+            RofiOutcome::Empty if min_len > 0 => notice = Some(length_notice.clone()),
+            outcome => return Ok(outcome),
+        }
+    }
+}
+
+/// The non-empty lines of an `--entries-from-file`
+fn parse_entries(text: &str) -> Vec<&str> {
+    text.lines().filter(|line| !line.trim().is_empty()).collect()
+}
+
+/// Let the user pick one of the `--entries-from-file` lines, read afresh for every `GETPIN`
+fn choose_entry(state: &State, args: &RofiArgs, path: &Path, rofi: &mut dyn Rofi) -> io::Result<RofiOutcome> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => Zeroizing::new(text),
+        Err(err) => return Ok(RofiOutcome::Error(format!("{}: {err}", path.display()))),
+    };
+    let entries = parse_entries(&text);
+    if entries.is_empty() {
+        return Ok(RofiOutcome::Error(format!("{}: no entries", path.display())));
+    }
+
+    let mut choose_args = state.rofi_args.clone();
+    choose_args.remove("-password");
+    choose_args.remove("-input");
+    choose_args.insert("-no-custom".to_owned(), None);
+    choose_args.insert("-l".to_owned(), Some(entries.len().to_string()));
+    if let Some(matching) = args.matching {
+        choose_args.insert("-matching".to_owned(), Some(matching.as_rofi_arg().to_owned()));
+    }
+    apply_newline_repr(&mut choose_args, args);
+
+    let output = with_hooks(args, || {
+        rofi.run(
+            &build_rofi_command(&choose_args, args),
+            Some(&Zeroizing::new(entries.join("\n"))),
+            state.timeout,
+            &state.rofi_env(),
+        )
+    })?;
+    Ok(match RofiOutcome::from(output) {
+        RofiOutcome::Pin(selected) if !entries.contains(&selected.as_str()) => RofiOutcome::Cancelled,
+        outcome => outcome,
+    })
+}
+
+/// Answer for `GETPIN`: a typed passphrase, or a pick from `--entries-from-file`
+fn ask_pin(state: &State, args: &RofiArgs, rofi: &mut dyn Rofi) -> io::Result<RofiOutcome> {
+    notify(&args.notify);
+    match &args.entries_from_file {
+        Some(path) => choose_entry(state, args, path, rofi),
+        None => run_rofi(state, args, rofi),
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ConfirmFlags {
+    one_button: bool,
+}
+
+/// Split the leading `--flag` tokens off a `CONFIRM` argument. Unknown flags and
+/// trailing text are rejected.
+fn parse_confirm_flags(arg: &str) -> Result<ConfirmFlags, String> {
+    let mut flags = ConfirmFlags::default();
+    for token in arg.split_whitespace() {
+        match token {
+            "--one-button" => flags.one_button = true,
+            _ => return Err(token.to_owned()),
+        }
+    }
+    Ok(flags)
+}
+
+/// Answer of a confirmation dialog
+#[derive(Debug, PartialEq)]
+enum Confirmation {
+    Confirmed,
+    NotConfirmed,
+    /// Dismissed or failed, with rofi's error detail if any
+    Cancelled(String),
+    /// rofi was killed by this signal
+    Killed(i32),
+}
+
+/// Show `mesg` (the description by default) with OK/Cancel entries, or only OK if `one_button`
+fn confirm_dialog(
+    state: &State,
+    args: &RofiArgs,
+    mesg: Option<String>,
+    one_button: bool,
+    rofi: &mut dyn Rofi,
+) -> io::Result<Confirmation> {
+    let ok_label = state.label("default-ok", "OK");
+    let cancel_label = state.label("default-cancel", "Cancel");
+    let entries = if one_button {
+        vec![ok_label.as_str()]
+    } else {
+        vec![ok_label.as_str(), cancel_label.as_str()]
+    };
+
+    let mut confirm_args = state.rofi_args.clone();
+    confirm_args.remove("-password");
+    confirm_args.remove("-input");
+    confirm_args.insert("-no-custom".to_owned(), None);
+    confirm_args.insert("-l".to_owned(), Some(entries.len().to_string()));
+    if let Some(mesg) = mesg {
+        confirm_args.insert("-mesg".to_owned(), Some(mesg));
+    }
+    if let Some(matching) = args.matching {
+        confirm_args.insert("-matching".to_owned(), Some(matching.as_rofi_arg().to_owned()));
+    }
+    // Only on request. `OPTION default-yes`/`default-no` are button labels, not a choice.
+    if let Some(focus) = args.confirm_default_focus {
+        let row = match focus {
+            ConfirmFocus::Safe if !one_button => "1",
+            ConfirmFocus::Safe | ConfirmFocus::Affirmative => "0",
+        };
+        confirm_args.insert("-selected-row".to_owned(), Some(row.to_owned()));
+    }
+    apply_newline_repr(&mut confirm_args, args);
+
+    let timeout = args.confirm_timeout.map(Duration::from_secs).or(state.timeout);
+    let output = with_hooks(args, || {
+        rofi.run(
+            &build_rofi_command(&confirm_args, args),
+            Some(&entries.join("\n")),
+            timeout,
+            &state.rofi_env(),
+        )
+    })?;
+    Ok(match RofiOutcome::from(output) {
+        // Whatever was picked or dismissed, a single button was seen
+        RofiOutcome::Pin(_) | RofiOutcome::Empty | RofiOutcome::Cancelled if one_button => Confirmation::Confirmed,
+        RofiOutcome::Pin(selected) if *selected == ok_label => Confirmation::Confirmed,
+        RofiOutcome::Pin(selected) if *selected == cancel_label => Confirmation::NotConfirmed,
+        RofiOutcome::Pin(_) | RofiOutcome::Empty => match args.confirm_on_empty {
+            ConfirmOnEmpty::Cancel => Confirmation::Cancelled(String::new()),
+            ConfirmOnEmpty::NotConfirmed => Confirmation::NotConfirmed,
+        },
+        RofiOutcome::Cancelled | RofiOutcome::Keybind(_) => Confirmation::Cancelled(String::new()),
+        RofiOutcome::Error(err) => Confirmation::Cancelled(err),
+        RofiOutcome::Killed(signal) => Confirmation::Killed(signal),
+    })
+}
+
+/// Run the `CONFIRM` dialog. Sends `ERR` and returns false unless the user picked OK.
+fn run_confirm(
+    state: &State,
+    args: &RofiArgs,
+    flags: &ConfirmFlags,
+    rofi: &mut dyn Rofi,
+    writer: &mut Writer<impl Write>,
+) -> io::Result<bool> {
+    let confirmation = match args.auto_confirm {
+        // A single button has nothing to say no with
+        AutoConfirm::No if !flags.one_button => Ok(Confirmation::NotConfirmed),
+        AutoConfirm::Yes | AutoConfirm::No => Ok(Confirmation::Confirmed),
+        AutoConfirm::Never => {
+            report_outcome(state, args, DialogOutcome::Shown);
+            confirm_dialog(state, args, None, flags.one_button, rofi)
+        }
+    };
+    report_outcome(
+        state,
+        args,
+        match &confirmation {
+            Ok(Confirmation::Confirmed) => DialogOutcome::Confirmed,
+            Ok(Confirmation::NotConfirmed) => DialogOutcome::NotConfirmed,
+            Ok(Confirmation::Cancelled(err)) if err.is_empty() => DialogOutcome::Cancelled,
+            _ => DialogOutcome::Error,
+        },
+    );
+    if args.confirm_status_line {
+        match confirmation {
+            Ok(Confirmation::Confirmed) => writer.assuan_send("S CONFIRM_OK")?,
+            Ok(Confirmation::NotConfirmed) => writer.assuan_send("S CONFIRM_CANCEL")?,
+            _ => {}
+        }
+    }
+    match confirmation {
+        Ok(Confirmation::Confirmed) => Ok(true),
+        Ok(Confirmation::NotConfirmed) => {
+            writer.assuan_send(
+                format!(
+                    "ERR {} Not confirmed",
+                    gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_NOT_CONFIRMED)
+                )
+                .as_ref(),
+            )?;
+            Ok(false)
+        }
+        Ok(Confirmation::Cancelled(err)) => {
+            send_cancelled(&err, state.lc_messages(), writer)?;
+            Ok(false)
+        }
+        Ok(Confirmation::Killed(signal)) => {
+            send_failed(&format!("rofi terminated by signal {signal}"), writer)?;
+            Ok(false)
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+            send_timeout(state.lc_messages(), writer)?;
+            Ok(false)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            send_no_pinentry(localized(Message::RofiNotFound, state.lc_messages()), writer)?;
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The "try N of M" gpg-agent puts into `SETERROR` on a bad passphrase
+fn parse_retry(error: &str) -> Option<(u32, u32)> {
+    let (_, rest) = error.rsplit_once("try ")?;
+    let (try_n, rest) = rest.split_once(" of ")?;
+    let of = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+    Some((try_n.parse().ok()?, of.parse().ok()?))
+}
+
+/// Assuan arguments percent-escape `%`, CR, LF and other control characters. Report the first
+/// violation.
+fn check_assuan_encoding(arg: &str) -> Result<(), String> {
+    let bytes = arg.as_bytes();
+    for (pos, &byte) in bytes.iter().enumerate() {
+        if byte == b'%'
+            && !bytes
+                .get(pos + 1..pos + 3)
+                .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+        {
+            return Err(format!("bad percent escape at byte {pos}"));
+        }
+        if byte.is_ascii_control() {
+            return Err(format!("raw control character 0x{byte:02x} at byte {pos}"));
+        }
+    }
+    Ok(())
+}
+
+/// Percent-decode an Assuan argument, keeping it raw if it doesn't decode to UTF-8
+fn percent_decode(text: &str) -> String {
+    decode(text).map(Cow::into_owned).unwrap_or_else(|_| text.to_owned())
+}
+
+/// Escape plain text for rofi `-mesg` markup
+fn escape_mesg(text: &str) -> String {
+    markup_escape_text(&text.replace('\n', "\r")).to_string()
+}
+
+/// The allow-listed tag `tag` opens, if any
+fn safe_open_tag(tag: &str) -> Option<&'static str> {
+    match tag {
+        "<b>" => Some("b"),
+        "<i>" => Some("i"),
+        _ => {
+            let color = tag.strip_prefix("<span foreground=\"")?.strip_suffix("\">")?;
+            let valid = !color.is_empty() && color.chars().all(|c| c == '#' || c.is_ascii_alphanumeric());
+            valid.then_some("span")
+        }
+    }
+}
+
+/// Escape plain text for rofi `-mesg` markup, except for balanced `<b>`, `<i>` and
+/// `<span foreground="…">` tags. Tags left open are closed at the end.
+fn safe_markup(text: &str) -> String {
+    let mut mesg = String::new();
+    let mut open = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        mesg.push_str(&escape_mesg(&rest[..start]));
+        rest = &rest[start..];
+        let tag = &rest[..rest.find('>').map_or(0, |end| end + 1)];
+        if let Some(name) = safe_open_tag(tag) {
+            open.push(name);
+        } else if open.last().is_some_and(|name| tag == format!("</{name}>")) {
+            open.pop();
+        } else {
+            mesg.push_str("&lt;");
+            rest = &rest[1..];
+            continue;
+        }
+        mesg.push_str(tag);
+        rest = &rest[tag.len()..];
+    }
+    mesg.push_str(&escape_mesg(rest));
+    for name in open.iter().rev() {
+        mesg.push_str(&format!("</{name}>"));
+    }
+    mesg
+}
+
+/// Turn an Assuan text argument into escaped rofi `-mesg` markup
+fn to_mesg(text: &str) -> String {
+    escape_mesg(&percent_decode(text))
+}
+
+/// Turn a `SETDESC` argument into `-mesg` markup. Lines are kept, and each run of blank lines
+/// becomes exactly one empty line, so paragraphs stay apart without uneven gaps. Past `max_lines`
+/// the rest is cut and the last line kept ends with `…`.
+fn desc_to_mesg(desc: &str, max_lines: Option<usize>, markup: DescMarkup) -> String {
+    let desc = percent_decode(desc);
+    let mut paragraphs = Vec::new();
+    let mut lines = Vec::new();
+    for line in desc.lines() {
+        if !line.trim().is_empty() {
+            lines.push(line);
+        } else if !lines.is_empty() {
+            paragraphs.push(lines.join("\n"));
+            lines.clear();
+        }
+    }
+    if !lines.is_empty() {
+        paragraphs.push(lines.join("\n"));
+    }
+    let mut text = paragraphs.join("\n\n");
+    if let Some(max_lines) = max_lines.filter(|&max_lines| text.lines().count() > max_lines) {
+        let kept = text.lines().take(max_lines).collect::<Vec<_>>().join("\n");
+        text = format!("{}…", kept.trim_end());
+    }
+    match markup {
+        DescMarkup::Escape => escape_mesg(&text),
+        DescMarkup::Safe => safe_markup(&text),
+    }
+}
+
+/// Longest Assuan line, `D ` and the newline included
+const ASSUAN_LINE_LENGTH: usize = 1000;
+
+/// `data` as `D` lines: `%`, CR and LF percent-escaped, and cut at the Assuan line length
+/// without splitting an escape or a character. gpg-agent joins them back together.
+fn data_lines(data: &str) -> Vec<Zeroizing<String>> {
+    let new_line = || {
+        // Full size up front, so the secret is never left behind by a reallocation
+        let mut line = Zeroizing::new(String::with_capacity(ASSUAN_LINE_LENGTH));
+        line.push_str("D ");
+        line
+    };
+    let mut lines = Vec::new();
+    let mut line = new_line();
+    let mut buf = [0; 4];
+    for c in data.chars() {
+        let piece = match c {
+            '%' => "%25",
+            '\r' => "%0D",
+            '\n' => "%0A",
+            c => &*c.encode_utf8(&mut buf),
+        };
+        if line.len() + piece.len() + "\n".len() > ASSUAN_LINE_LENGTH {
+            lines.push(std::mem::replace(&mut line, new_line()));
+        }
+        line.push_str(piece);
+    }
+    lines.push(line);
+    lines
+}
+
+/// Open the client's tty without making it our controlling terminal or blocking on it. The fuzz
+/// target opens nothing: OPTION ttyname would otherwise let its input name any file.
+fn open_tty(path: &str) -> io::Result<std::fs::File> {
+    if cfg!(all(feature = "fuzz", not(test))) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "no tty while fuzzing"));
+    }
+    if path.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no tty known"));
+    }
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// Rows and columns of the terminal `tty`
+fn tty_size(tty: &impl AsRawFd) -> io::Result<(u16, u16)> {
+    // SAFETY: winsize is plain data, and TIOCGWINSZ only fills it in.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((size.ws_row, size.ws_col))
+}
+
+/// Backend followed by the features this session runs with, for `GETINFO backend`
+fn backend_features(args: &RofiArgs) -> Vec<&'static str> {
+    let mut features = vec!["rofi", "timeout"];
+    for (feature, enabled) in [
+        ("auto-confirm", !matches!(args.auto_confirm, AutoConfirm::Never)),
+        ("confirm-timeout", args.confirm_timeout.is_some()),
+        ("delegate", args.delegate_to.is_some()),
+        ("ime-friendly", args.ime_friendly),
+        ("kill-existing", args.kill_existing),
+        ("max-pin-len", args.max_pin_len.is_some()),
+        ("restricted", args.no_env_options),
+        ("show-attempts", args.show_attempts),
+    ] {
+        if enabled {
+            features.push(feature);
+        }
+    }
+    features
+}
+
+/// Cut the raw (still percent-encoded) description down to `max_len` bytes, never splitting
+/// a character or a `%XX` escape, and mark the cut with an ellipsis.
+fn truncate_desc(desc: &str, max_len: usize) -> Cow<'_, str> {
+    if desc.len() <= max_len {
+        return Cow::Borrowed(desc);
+    }
+    let mut end = max_len;
+    while !desc.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Bytes, as the two before the cut may be part of a multibyte character
+    let escape_start = end.saturating_sub(2);
+    if let Some(pos) = desc.as_bytes()[escape_start..end].iter().rposition(|&b| b == b'%') {
+        end = escape_start + pos;
+    }
+    Cow::Owned(format!("{}…", &desc[..end]))
+}
+
+/// Heuristic for values that should never end up in logs: long, no whitespace, not a path,
+/// mixing lower/upper case and digits, with high per-character entropy.
+fn looks_like_secret(value: &str) -> bool {
+    if value.chars().count() < 16 || value.starts_with('/') || value.contains(char::is_whitespace) {
+        return false;
+    }
+    let classes = [
+        value.contains(|c: char| c.is_lowercase()),
+        value.contains(|c: char| c.is_uppercase()),
+        value.contains(|c: char| c.is_ascii_digit()),
+    ];
+    if classes.iter().filter(|&&class| class).count() < 3 {
+        return false;
+    }
+
+    let mut counts = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let len = value.chars().count() as f64;
+    let entropy: f64 = counts
+        .values()
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    entropy >= 3.5
+}
+
+/// `line` for the log, with each space or `=` separated word that `looks_like_secret` replaced
+fn redact_secrets(line: &str) -> Cow<'_, str> {
+    if !line.split([' ', '=']).any(looks_like_secret) {
+        return Cow::Borrowed(line);
+    }
+    let mut redacted = String::with_capacity(line.len());
+    for word in line.split_inclusive([' ', '=']) {
+        let (word, separator) = match word.strip_suffix([' ', '=']) {
+            Some(rest) => word.split_at(rest.len()),
+            None => (word, ""),
+        };
+        redacted.push_str(if looks_like_secret(word) { "[redacted]" } else { word });
+        redacted.push_str(separator);
+    }
+    Cow::Owned(redacted)
+}
+
+#[derive(Debug)]
+struct UnknownAction {
+    action: String,
+    arg: String,
+}
+
+impl fmt::Display for UnknownAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown assuan command. Action `{0}`. Argument `{1}`",
+            self.action, self.arg
+        )
+    }
+}
+
+impl error::Error for UnknownAction {}
+
+fn handle_command<'a, W: Write>(
+    action: &'a str,
+    arg: &'a str,
+    state: &'a mut State,
+    args: &'a RofiArgs,
+    rofi: &'a mut dyn Rofi,
+    writer: &'a mut Writer<W>,
+) -> BoxResult<()> {
+    let mut ok = true;
+    // Informational text after `OK`, bare `OK` if `None`
+    let mut ok_text = None;
+    // `GETINFO ` and friends: a trailing space is no argument
+    let arg = arg.trim_end();
+    if args.assuan_strict_encoding {
+        if let Err(err) = check_assuan_encoding(arg) {
+            writer.assuan_send(
+                format!(
+                    "ERR {} IPC syntax error <{err}>",
+                    gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_ASS_SYNTAX)
+                )
+                .as_ref(),
+            )?;
+            return Ok(());
+        }
+    }
+
+    if matches!(action, "GETPIN" | "CONFIRM" | "MESSAGE") {
+        state.title_shown = true;
+    }
+
+    match (action, arg) {
+        ("OPTION" | "GETINFO", "") => {
+            send_invalid_argument(action, arg, writer)?;
+            return Ok(());
+        }
+        ("OPTION", arg) => {
+            // Only the first `=` separates; the value keeps any others
+            let (opt, val) = arg.split_once('=').unwrap_or((arg, ""));
+            let val = percent_decode(val);
+            if looks_like_secret(&val) {
+                writer.assuan_send(format!("# Value of OPTION {opt} looks like a secret, redacted").as_ref())?;
+            }
+            // A NUL can't go into the environment
+            if let Some(var) = args.env_var_for(opt).filter(|_| !val.contains('\0')) {
+                env::set_var(var, &val);
+            }
+            if opt == "grab" && args.ime_friendly {
+                info!("Not grabbing the keyboard despite OPTION grab: --ime-friendly");
+                writer.warn_to_client("OPTION grab ignored, --ime-friendly keeps the keyboard ungrabbed")?;
+            }
+            // The client's own word on its tty replaces what was worked out from the environment
+            if matches!(opt, "ttyname" | "ttytype") {
+                state.info_replies.remove("ttyinfo");
+            }
+            state.options.insert(opt.to_owned(), val);
+        }
+        ("GETINFO", "pid") => writer.assuan_send(format!("D {}", process::id()).as_ref())?,
+        ("GETINFO", "ttyinfo") => {
+            let reply = state.info_reply("ttyinfo", |state| {
+                format!(
+                    "D {0} {1} {2}",
+                    state.option_or_env("ttyname", "GPG_TTY"),
+                    state.option_or_env("ttytype", "GPG_TERM"),
+                    args.display
+                )
+            });
+            writer.assuan_send(&reply)?
+        }
+        ("GETINFO", "flavor") => {
+            let reply = state.info_reply("flavor", |_| format!("D {}", flavor()));
+            writer.assuan_send(&reply)?
+        }
+        ("GETINFO", "version") => {
+            let reply = state.info_reply("version", |_| format!("D {}", version()));
+            writer.assuan_send(&reply)?
+        }
+        // Restricted: OPTIONs never reach the environment
+        ("GETINFO", "restricted") => writer.assuan_send(if args.no_env_options { "D 1" } else { "D 0" })?,
+        ("GETINFO", "ttysize") => {
+            let tty = state.option_or_env("ttyname", "GPG_TTY");
+            match open_tty(&tty).and_then(|tty| tty_size(&tty)) {
+                Ok((rows, cols)) => writer.assuan_send(format!("D {rows} {cols}").as_ref())?,
+                Err(err) => {
+                    writer.assuan_send(
+                        format!(
+                            "ERR {} Not supported <no size for tty `{tty}`: {err}>",
+                            gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_NOT_SUPPORTED)
+                        )
+                        .as_ref(),
+                    )?;
+                    return Ok(());
+                }
+            }
+        }
+        // rofi has no progress bar, so no `S PROGRESS` is ever sent
+        ("GETINFO", "cmd_progress") => writer.assuan_send("D 0")?,
+        ("GETINFO", "backend") => writer.assuan_send(format!("D {}", backend_features(args).join(";")).as_ref())?,
+        ("GETINFO", arg) => {
+            send_invalid_argument(action, arg, writer)?;
+            return Ok(());
+        }
+        ("SETPROMPT", arg) => {
+            state.pin_prompt = is_pin_prompt(&percent_decode(arg));
+            if !state.rofi_args.contains_key("-p") {
+                state.rofi_args.insert("-p".to_owned(), Some(arg.replace(":", "")));
+            }
+        }
+        ("SETDESC", arg) => {
+            let prefix = args.desc_prefix.as_deref().filter(|prefix| !prefix.is_empty());
+            let max_len = args.max_desc_len.saturating_sub(prefix.map_or(0, str::len));
+            state.desc_context = passphrase_context(&percent_decode(arg));
+            let desc = desc_to_mesg(&truncate_desc(arg, max_len), args.desc_max_lines, args.desc_markup);
+            let desc = Some(match prefix {
+                Some(prefix) if desc.is_empty() => escape_mesg(prefix),
+                Some(prefix) => format!("{}\r\r{desc}", escape_mesg(prefix)),
+                None => desc,
+            });
+            state.drop_stale_title(args, desc != state.desc);
+            state.desc = desc;
+            state.update_mesg();
+        }
+        ("SETTIMEOUT", arg) => {
+            let secs = arg.trim().parse().unwrap_or(0);
+            state.timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        #[cfg(feature = "insecure-testing")]
+        ("GETPIN", _) if args.insecure_pin_from_env.is_some() => {
+            let var = args.insecure_pin_from_env.as_deref().unwrap_or_default();
+            warn!("Answering GETPIN from ${var} without asking: --insecure-pin-from-env is for testing only");
+            writer.warn_to_client(&format!(
+                "INSECURE: passphrase taken from ${var} without asking, for testing only"
+            ))?;
+            match env::var(var) {
+                Ok(pin) => {
+                    let pin = Zeroizing::new(pin);
+                    for line in data_lines(&pin) {
+                        writer.assuan_send(&line)?;
+                    }
+                }
+                Err(err) => {
+                    send_cancelled(&format!("${var}: {err}"), state.lc_messages(), writer)?;
+                    return Ok(());
+                }
+            }
+        }
+        ("GETPIN" | "CONFIRM" | "MESSAGE", _) if state.loopback() => {
+            writer.assuan_send(
+                format!(
+                    "ERR {} Not supported <loopback pinentry mode>",
+                    gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_NOT_SUPPORTED)
+                )
+                .as_ref(),
+            )?;
+            report_outcome(state, args, DialogOutcome::Error);
+            return Ok(());
+        }
+        ("GETPIN" | "CONFIRM" | "MESSAGE", _) if !args.no_display_check && !rofi.display_available(&args.display) => {
+            send_no_pinentry(&format!("display `{}` is unavailable", args.display), writer)?;
+            report_outcome(state, args, DialogOutcome::Error);
+            return Ok(());
+        }
+        ("GETPIN", _) => {
+            if let Some(warning) = rofi.masking_warning() {
+                writer.warn_to_client(&warning)?;
+            }
+            report_outcome(state, args, DialogOutcome::Shown);
+            let history = args.disable_history_verify.then(|| history_snapshot(&rofi_cache_dir()));
+            let outcome = ask_pin(state, args, rofi);
+            if let Some(before) = history {
+                let changed = changed_history(&before, &history_snapshot(&rofi_cache_dir()));
+                if !changed.is_empty() {
+                    let files = changed
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>();
+                    error!("rofi wrote its history despite -disable-history: {}", files.join(", "));
+                    writer.warn_to_client(&format!(
+                        "rofi may have written the input to its history: {}",
+                        files.join(", ")
+                    ))?;
+                }
+            }
+            report_outcome(
+                state,
+                args,
+                match &outcome {
+                    Ok(RofiOutcome::Pin(_) | RofiOutcome::Empty) => DialogOutcome::PinEntered,
+                    Ok(RofiOutcome::Cancelled | RofiOutcome::Keybind(_)) => DialogOutcome::Cancelled,
+                    _ => DialogOutcome::Error,
+                },
+            );
+            match outcome {
+                // An answered prompt used up the error; a cancelled one shows it again on retry
+                Ok(RofiOutcome::Pin(pin)) => {
+                    for line in data_lines(&pin) {
+                        writer.assuan_send(&line)?;
+                    }
+                    state.clear_error();
+                }
+                Ok(RofiOutcome::Empty) => state.clear_error(),
+                Ok(RofiOutcome::Cancelled | RofiOutcome::Keybind(_)) => {
+                    send_cancelled("", state.lc_messages(), writer)?;
+                    return Ok(());
+                }
+                Ok(RofiOutcome::Error(err)) => {
+                    send_cancelled(&err, state.lc_messages(), writer)?;
+                    return Ok(());
+                }
+                Ok(RofiOutcome::Killed(signal)) => {
+                    send_failed(&format!("rofi terminated by signal {signal}"), writer)?;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    send_timeout(state.lc_messages(), writer)?;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    send_cancelled(&err.to_string(), state.lc_messages(), writer)?;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    send_no_pinentry(localized(Message::RofiNotFound, state.lc_messages()), writer)?;
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        ("CONFIRM", arg) => match parse_confirm_flags(arg) {
+            Ok(flags) => {
+                if !run_confirm(state, args, &flags, rofi, writer)? {
+                    return Ok(());
+                }
+            }
+            Err(flag) => {
+                send_invalid_argument(action, &flag, writer)?;
+                return Ok(());
+            }
+        },
+        // `CONFIRM --one-button`, after which the message is used up so the next dialog only
+        // shows what was set for it
+        ("MESSAGE", _) => {
+            let shown = run_confirm(state, args, &ConfirmFlags { one_button: true }, rofi, writer);
+            state.clear_message();
+            if !shown? {
+                return Ok(());
+            }
+        }
+        ("SETERROR", arg) => {
+            let error = if args.no_markup_in_error {
+                to_mesg(arg)
+            } else {
+                arg.to_owned()
+            };
+            state.error = Some(error).filter(|error| !error.is_empty());
+            state.update_mesg();
+            state.retry = parse_retry(arg);
+            state.update_window_title();
+        }
+        ("SETTITLE", arg) => {
+            state.title = Some(percent_decode(arg)).filter(|title| !title.is_empty());
+            state.title_shown = false;
+            state.update_window_title();
+        }
+        ("SETKEYINFO", arg) => {
+            // `--clear` has no `/` and forgets the key
+            let keyinfo = arg.split_once('/');
+            let keygrip = keyinfo.map(|(_, keygrip)| keygrip.to_uppercase());
+            state.drop_stale_title(args, keygrip != state.keygrip);
+            state.keygrip = keygrip;
+            state.keyinfo_context = keyinfo.and_then(|(mode, _)| keyinfo_context(mode));
+        }
+        ("RESET", _) => state.reset(),
+        // Refused, so gpg-agent asks twice itself; only noted for `--password-min-length`. Known,
+        // so no reason for `--exit-on-first-error` to give up.
+        ("SETREPEAT", _) => {
+            state.new_passphrase = true;
+            writer.assuan_send(
+                format!(
+                    "ERR {} Not supported <no repeat entry>",
+                    gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_NOT_SUPPORTED)
+                )
+                .as_ref(),
+            )?;
+            return Ok(());
+        }
+        ("BYE", _) => ok_text = Some("closing connection"),
+        _ => ok = false,
+    }
+
+    if ok {
+        match ok_text {
+            Some(text) => writer.assuan_send(format!("OK {text}").as_ref())?,
+            None => writer.assuan_send("OK")?,
+        }
+    } else if !args.exit_on_first_error {
+        writer.assuan_send(
+            format!(
+                "ERR {} Unknown IPC command",
+                gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_ASS_UNKNOWN_CMD)
+            )
+            .as_ref(),
+        )?;
+    } else {
+        let arg = if arg.split([' ', '=']).any(looks_like_secret) {
+            writer.assuan_send(format!("# Argument of {action} looks like a secret, redacted").as_ref())?;
+            "[redacted]"
+        } else {
+            arg
+        };
+        writer.assuan_send("BYE")?;
+        return Err(Box::new(UnknownAction {
+            action: action.to_owned(),
+            arg: arg.to_owned(),
+        }));
+    }
+    Ok(())
+}
+
+/// Best-effort check that rofi can show up: a Wayland session, a local X11 socket for `:N`
+/// displays, or any remote `host:N` display. X servers only listening on an abstract socket
+/// fail it, hence `--no-display-check`.
+fn display_available(display: &str, wayland_display: Option<&str>) -> bool {
+    if wayland_display.is_some_and(|wayland| !wayland.is_empty()) {
+        return true;
+    }
+    match display.split_once(':') {
+        Some(("", screen)) => {
+            let num = screen.split('.').next().unwrap_or_default();
+            Path::new("/tmp/.X11-unix").join(format!("X{num}")).exists()
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Where rofi keeps its history: `$XDG_CACHE_HOME`, or `~/.cache`
+fn rofi_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".cache"))
+}
+
+/// Size and modification time of the `rofi*` files in `dir`. A missing dir has none.
+fn history_snapshot(dir: &Path) -> HashMap<PathBuf, (u64, Option<SystemTime>)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("rofi"))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), (metadata.len(), metadata.modified().ok())))
+        })
+        .collect()
+}
+
+/// Files created or modified between two snapshots, sorted
+fn changed_history(
+    before: &HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    after: &HashMap<PathBuf, (u64, Option<SystemTime>)>,
+) -> Vec<PathBuf> {
+    let mut changed: Vec<_> = after
+        .iter()
+        .filter(|(path, stat)| before.get(*path) != Some(stat))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Whether `binary`, looked up in `path` the way exec does, is the executable `self_exe`
+fn resolves_to_self(binary: &str, path: Option<&OsStr>, self_exe: &Path) -> bool {
+    let Ok(self_exe) = self_exe.canonicalize() else {
+        return false;
+    };
+    let candidates: Vec<PathBuf> = if binary.contains('/') {
+        vec![binary.into()]
+    } else {
+        env::split_paths(path.unwrap_or_default())
+            .map(|dir| dir.join(binary))
+            .collect()
+    };
+    candidates
+        .iter()
+        .find(|candidate| candidate.is_file())
+        .and_then(|candidate| candidate.canonicalize().ok())
+        .is_some_and(|candidate| candidate == self_exe)
+}
+
+/// Per-user file naming the pinentry-rofi currently in charge of prompting
+fn pidfile_path() -> PathBuf {
+    let dir = env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from);
+    // SAFETY: getuid can't fail.
+    dir.join(format!("pinentry-rofi-{}.pid", unsafe { libc::getuid() }))
+}
+
+fn read_pidfile(path: &Path) -> Option<libc::pid_t> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Whether the pidfile exists but names no running process: unreadable, or left behind by a
+/// pinentry-rofi that didn't get to remove it
+fn stale_pidfile(path: &Path) -> bool {
+    if !path.exists() {
+        return false;
+    }
+    match read_pidfile(path).filter(|&pid| pid > 0) {
+        Some(pid) => {
+            // SAFETY: signal 0 only checks that the pid exists. EPERM means it does.
+            let alive = unsafe { libc::kill(pid, 0) } == 0;
+            !alive && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        }
+        None => true,
+    }
+}
+
+/// Our pid in the pidfile for the session. Removed on drop unless another instance took over.
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Replace whatever is at `path` with a new file. Never follows a symlink, which anyone can
+    /// plant in the temp dir fallback of `pidfile_path`.
+    fn create(path: &Path) -> io::Result<Self> {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile { path: path.to_owned() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if read_pidfile(&self.path) == Some(process::id() as libc::pid_t) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Values of `vars` when captured, put back on drop
+struct EnvGuard {
+    saved: Vec<(&'static str, Option<OsString>)>,
+}
+
+impl EnvGuard {
+    fn capture(vars: &[&'static str]) -> Self {
+        EnvGuard {
+            saved: vars.iter().map(|var| (*var, env::var_os(var))).collect(),
+        }
+    }
+}
+
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        for (var, value) in &self.saved {
+            match value {
+                Some(value) => env::set_var(var, value),
+                None => env::remove_var(var),
+            }
+        }
+    }
+}
+
+/// SIGTERM the pinentry-rofi named in the pidfile; its rofi follows through `PR_SET_PDEATHSIG`.
+/// Pids that are ours or no longer run `self_exe` (stale file, reused pid) are left alone.
+fn kill_existing(path: &Path, self_exe: &Path) -> Option<libc::pid_t> {
+    let pid = read_pidfile(path).filter(|&pid| pid > 0 && pid != process::id() as libc::pid_t)?;
+    if process_exe(pid)? != self_exe.canonicalize().ok()? {
+        return None;
+    }
+    // SAFETY: plain syscall on a pid we just checked.
+    (unsafe { libc::kill(pid, libc::SIGTERM) } == 0).then_some(pid)
+}
+
+/// Executable `pid` runs, as far as the OS tells. Only Linux does, through `/proc`; elsewhere
+/// `--kill-existing` can't tell our instances from reused pids and leaves them alone.
+#[cfg(target_os = "linux")]
+fn process_exe(pid: libc::pid_t) -> Option<PathBuf> {
+    Path::new("/proc").join(pid.to_string()).join("exe").canonicalize().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_exe(_pid: libc::pid_t) -> Option<PathBuf> {
+    None
+}
+
+/// Run another pinentry on the given connection and wait for it to finish the session.
+fn delegate_to(binary: &str, stdin: Stdio, stdout: Stdio) -> io::Result<process::ExitStatus> {
+    process::Command::new(binary).stdin(stdin).stdout(stdout).status()
+}
+
+pub fn pinentry(args_matches: &ArgMatches) -> BoxResult<()> {
+    run_pinentry(args_matches, None)
+}
+
+/// [`pinentry`], telling `observer` about each dialog
+pub fn pinentry_with_observer(args_matches: &ArgMatches, observer: Rc<dyn DialogObserver>) -> BoxResult<()> {
+    run_pinentry(args_matches, Some(observer))
+}
+
+fn run_pinentry(args_matches: &ArgMatches, observer: Option<Rc<dyn DialogObserver>>) -> BoxResult<()> {
+    let args = RofiArgs::from_arg_matches(args_matches)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let level = args.log_filter();
+    if log::set_boxed_logger(Box::new(Logger::new(log_sink(&args)?, level))).is_ok() {
+        log::set_max_level(level);
+    }
+
+    if let Some(binary) = &args.delegate_to {
+        if !args.no_display_check && !display_available(&args.display, env::var("WAYLAND_DISPLAY").ok().as_deref()) {
+            info!("Display `{}` is unavailable, delegating to `{binary}`", args.display);
+            let status = delegate_to(binary, Stdio::inherit(), Stdio::inherit())?;
+            if !status.success() {
+                return Err(format!("Delegate `{binary}` failed: {status}").into());
+            }
+            return Ok(());
+        }
+    }
+
+    if resolves_to_self(
+        &args.rofi_bin,
+        env::var_os("PATH").as_deref(),
+        Path::new("/proc/self/exe"),
+    ) {
+        return Err(format!("--rofi-bin `{}` is pinentry-rofi itself, not rofi", args.rofi_bin).into());
+    }
+
+    let _pidfile = match args.pid_file.clone().or_else(|| args.kill_existing.then(pidfile_path)) {
+        Some(path) => {
+            if stale_pidfile(&path) {
+                info!("Replacing the stale pidfile {}", path.display());
+            } else if args.kill_existing {
+                if let Some(pid) = kill_existing(&path, Path::new("/proc/self/exe")) {
+                    info!("Terminated the earlier pinentry-rofi {pid}");
+                }
+            }
+            Some(PidFile::create(&path).map_err(|err| format!("{}: {err}", path.display()))?)
+        }
+        None => None,
+    };
+
+    let (mut state, _input) = initial_state(&args, observer)?;
+
+    let mut rofi = RofiProcess::new(&args);
+    let env_guard = || {
+        args.reset_env_on_exit
+            .then(|| EnvGuard::capture(&ENV_OPTIONS.map(|(_, var)| var)))
+    };
+    if let Some(path) = &args.listen {
+        let socket = bind_socket(path).map_err(|err| format!("--listen `{}`: {err}", path.display()))?;
+        remove_socket_on_signal(path);
+        info!("Listening on {}", path.display());
+        for stream in socket.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // E.g. out of file descriptors for a moment; the next client may well get through
+                Err(err) => {
+                    error!("Accepting a connection failed: {err}");
+                    continue;
+                }
+            };
+            let mut session = state.for_new_connection();
+            let _env = env_guard();
+            if let Err(err) = serve_connection(stream, &mut session, &args, &mut rofi) {
+                error!("Connection closed: {err}");
+            }
+        }
+        return Ok(());
+    }
+    let _env = env_guard();
+    let mut writer = match (args.flush_timeout, args.no_flush_per_line) {
+        (None, false) => Writer::stdout(),
+        (None, true) => Writer::buffered(Box::new(io::BufWriter::new(io::stdout())) as Box<dyn Write>),
+        (Some(ms), no_flush_per_line) => {
+            let stdout = TimedWriter::new(
+                std::fs::File::from(io::stdout().as_fd().try_clone_to_owned()?),
+                Duration::from_millis(ms),
+            );
+            if no_flush_per_line {
+                Writer::buffered(Box::new(io::BufWriter::new(stdout)) as Box<dyn Write>)
+            } else {
+                Writer::new(Box::new(stdout) as Box<dyn Write>)
+            }
+        }
+    };
+    serve(io::stdin().lock(), &mut state, &args, &mut rofi, &mut writer)
+}
+
+/// Session state for the command line, and the `--prompt-history-salt` input file, removed when
+/// dropped, which has to outlive it
+fn initial_state(
+    args: &RofiArgs,
+    observer: Option<Rc<dyn DialogObserver>>,
+) -> BoxResult<(State, Option<tempfile::NamedTempFile>)> {
+    let key_profiles = match &args.keygrip_map {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+            parse_key_profiles(&text).map_err(|err| format!("{}: {err}", path.display()))?
+        }
+        None => HashMap::new(),
+    };
+
+    let mut state = State {
+        key_profiles,
+        observer,
+        rofi_args: base_rofi_args(args)?,
+        ..Default::default()
+    };
+
+    let input = if args.prompt_history_salt {
+        Some(use_temp_input(&mut state.rofi_args)?)
+    } else {
+        None
+    };
+
+    if let Some(monitor) = &args.monitor {
+        let monitor = monitor_arg(monitor, || xrandr_outputs(&args.display, PROBE_TIMEOUT))?;
+        state.rofi_args.insert("-m".to_owned(), Some(monitor));
+    }
+
+    let prompt = match &args.prompt {
+        Some(prompt) if args.sanitize_prompt => Some(sanitize_prompt(prompt)),
+        prompt => prompt.clone(),
+    };
+    if let Some(prompt) = prompt.filter(|prompt| !prompt.is_empty()) {
+        state.rofi_args.insert("-p".to_owned(), Some(prompt));
+    }
+    Ok((state, input))
+}
+
+/// The `--listen` socket. Its file is removed on drop.
+struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on `path`, replacing the socket an earlier run left behind but no other kind of file
+fn bind_socket(path: &Path) -> io::Result<Socket> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(Socket {
+        listener: UnixListener::bind(path)?,
+        path: path.to_owned(),
+    })
+}
+
+/// The `--listen` socket path for `remove_socket_and_die`
+static SOCKET_PATH: std::sync::OnceLock<std::ffi::CString> = std::sync::OnceLock::new();
+
+/// Signal handler removing the `--listen` socket, then dying of `signal` as without a handler.
+/// Only async-signal-safe calls in here.
+extern "C" fn remove_socket_and_die(signal: libc::c_int) {
+    if let Some(path) = SOCKET_PATH.get() {
+        unsafe { libc::unlink(path.as_ptr()) };
+    }
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Have SIGTERM, SIGINT and SIGHUP remove the socket at `path`, which `Drop` doesn't get to do
+fn remove_socket_on_signal(path: &Path) {
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return;
+    };
+    if SOCKET_PATH.set(path).is_ok() {
+        let handler: extern "C" fn(libc::c_int) = remove_socket_and_die;
+        for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        }
+    }
+}
+
+/// Run one Assuan session over a `--listen` connection
+fn serve_connection(stream: UnixStream, state: &mut State, args: &RofiArgs, rofi: &mut dyn Rofi) -> BoxResult<()> {
+    let input = io::BufReader::new(stream.try_clone()?);
+    let mut writer = if args.no_flush_per_line {
+        Writer::buffered(Box::new(io::BufWriter::new(stream)) as Box<dyn Write>)
+    } else {
+        Writer::new(Box::new(stream) as Box<dyn Write>)
+    };
+    serve(input, state, args, rofi, &mut writer)
+}
+
+/// The rofi arguments every dialog starts from
+fn base_rofi_args(args: &RofiArgs) -> Result<HashMap<String, Option<String>>, String> {
+    let mut rofi_args = HashMap::from([
+        ("-dmenu".to_owned(), None),
+        ("-display".to_owned(), Some(args.display.to_owned())),
+        ("-input".to_owned(), Some("/dev/null".to_owned())),
+        ("-password".to_owned(), None),
+        ("-disable-history".to_owned(), None),
+        ("-l".to_owned(), Some("0".to_owned())),
+    ]);
+    if let Some(config) = &args.rofi_config {
+        if !config.is_file() {
+            return Err(format!("--rofi-config `{}` is not a file", config.display()));
+        }
+        rofi_args.insert("-config".to_owned(), Some(config.to_string_lossy().into_owned()));
+    }
+    Ok(rofi_args)
+}
+
+/// Replace `-input /dev/null` with a new empty file, removed when the returned handle is dropped
+fn use_temp_input(rofi_args: &mut HashMap<String, Option<String>>) -> io::Result<tempfile::NamedTempFile> {
+    let input = tempfile::Builder::new().prefix("pinentry-rofi-input-").tempfile()?;
+    rofi_args.insert("-input".to_owned(), Some(input.path().to_string_lossy().into_owned()));
+    Ok(input)
+}
+
+/// Connector names from `xrandr --listmonitors`, in monitor order, if xrandr answered within `timeout`
+fn xrandr_outputs(display: &str, timeout: Duration) -> Option<Vec<String>> {
+    let mut command = process::Command::new("xrandr");
+    command.args(["--display", display, "--listmonitors"]);
+    probe_output(&mut command, timeout).map(|text| parse_listmonitors(&text))
+}
+
+/// Connector names from `xrandr --listmonitors` output, lines like ` 0: +*DP-1 2560/597x1440/336+0+0  DP-1`
+fn parse_listmonitors(text: &str) -> Vec<String> {
+    text.lines()
+        .filter(|line| line.trim_start().starts_with(|c: char| c.is_ascii_digit()))
+        .filter_map(|line| line.split_whitespace().last().map(str::to_owned))
+        .collect()
+}
+
+/// rofi `-m` value for `--monitor`. Numbers go through as is; a name becomes its monitor index
+/// when the outputs are known, and is left for rofi to resolve when they aren't. `outputs` is only
+/// called for names, so a numeric monitor never waits on xrandr.
+fn monitor_arg(monitor: &str, outputs: impl FnOnce() -> Option<Vec<String>>) -> Result<String, String> {
+    if monitor.parse::<i32>().is_ok() {
+        return Ok(monitor.to_owned());
+    }
+    let Some(outputs) = outputs() else {
+        info!("Could not list outputs with xrandr, passing monitor `{monitor}` to rofi as is");
+        return Ok(monitor.to_owned());
+    };
+    outputs
+        .iter()
+        .position(|output| output == monitor)
+        .map(|index| index.to_string())
+        .ok_or_else(|| {
+            format!(
+                "--monitor `{monitor}` is not a connected output ({})",
+                outputs.join(", ")
+            )
+        })
+}
+
+/// How long `rofi -version` and `xrandr --listmonitors` may take before they are killed and
+/// taken as unanswered
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `rofi -version` output, if rofi ran and answered within `timeout`
+fn rofi_version(rofi_bin: &str, timeout: Duration) -> Option<String> {
+    probe_output(process::Command::new(rofi_bin).arg("-version"), timeout)
+}
+
+/// stdout of `command`, if it succeeded within `timeout`; killed otherwise
+fn probe_output(command: &mut process::Command, timeout: Duration) -> Option<String> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    if !wait_for_exit(&mut child, timeout, &mut thread::sleep).ok()? {
+        let _ = terminate(&mut child, TimeoutSignal::Kill, Duration::ZERO, thread::sleep);
+        let _ = child.wait();
+        return None;
+    }
+    let output = child.wait_with_output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// rofi masks `-password` input since 1.4. Anything older, or a version that can't be read,
+/// might show the passphrase as typed, so the client is told rather than left guessing.
+fn masking_warning(version: Option<&str>) -> Option<String> {
+    let parsed = version.and_then(|version| {
+        let number = version
+            .split_whitespace()
+            .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))?;
+        let mut parts = number.split(|c: char| !c.is_ascii_digit());
+        Some((parts.next()?.parse::<u32>().ok()?, parts.next()?.parse::<u32>().ok()?))
+    });
+    match parsed {
+        Some(version) if version >= (1, 4) => None,
+        Some((major, minor)) => Some(format!(
+            "rofi {major}.{minor} may not mask the passphrase, -password needs 1.4"
+        )),
+        None => Some("could not confirm that rofi masks the passphrase".to_owned()),
+    }
+}
+
+/// Feed each line of `data` to `handle_command` in one session, as `serve` would.
+/// Errors are expected; panics are what the fuzz target looks for.
+#[cfg(feature = "fuzz")]
+pub fn fuzz_handle_command(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else { return };
+    let args = RofiArgs::from_arg_matches(&cmd().get_matches_from(["pinentry-rofi"])).unwrap();
+    let mut state = State::default();
+    let mut writer = Writer::new(io::sink());
+    // An entry for every line, so each dialog gets one
+    let mut rofi = text
+        .lines()
+        .fold(testing::MockRofi::new(), |rofi, _| rofi.answer(0, "fuzz\n"));
+    for line in text.lines() {
+        let (action, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let _ = handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer);
+    }
+}
+
+/// Greet and answer `input` line by line. Responses are flushed before every read. Interrupted
+/// reads are retried; any other read error is answered with `ERR` and ends the session, as does
+/// running past `--max-connections-lifetime`.
+fn serve<W: Write>(
+    mut input: impl BufRead,
+    state: &mut State,
+    args: &RofiArgs,
+    rofi: &mut dyn Rofi,
+    writer: &mut Writer<W>,
+) -> BoxResult<()> {
+    let started = state.clock.now();
+    writer.assuan_send("OK Please go ahead")?;
+    writer.flush()?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match input.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                writer.assuan_send(
+                    format!(
+                        "ERR {} Read error <{err}>",
+                        gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_ASS_READ_ERROR)
+                    )
+                    .as_ref(),
+                )?;
+                writer.flush()?;
+                return Err(err.into());
+            }
+        }
+        let cmd = line.strip_suffix('\n').unwrap_or(&line);
+        let cmd = cmd.strip_suffix('\r').unwrap_or(cmd);
+        debug!("<- {}", redact_secrets(cmd));
+        let (action, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+        let result = handle_command(action, arg, state, args, rofi, writer);
+        writer.flush()?;
+        result?;
+        if let Some(secs) = args.max_connections_lifetime {
+            if state.clock.now() - started >= Duration::from_secs(secs) {
+                info!("Open for over {secs}s, closing the connection");
+                writer.warn_to_client(&format!("--max-connections-lifetime of {secs}s reached, closing"))?;
+                writer.flush()?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{rofi_output, MockRofi};
+    use clap::FromArgMatches;
+    use std::{
+        cell::Cell, collections::HashMap, env, os::unix::process::ExitStatusExt, process, rc::Rc, time::Duration,
+    };
+
+    struct AssuanEntry {
+        cmd: String,
+        etalon_output: String,
+        etalon_rofi_args: Option<HashMap<String, Option<String>>>,
+    }
+
+    fn prepare_test_handle_command(
+        custom_prompt: Option<String>,
+    ) -> (HashMap<String, Option<String>>, Vec<AssuanEntry>) {
+        let mut assuan_mapping = vec![
+            AssuanEntry {
+                cmd: "OPTION grab".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION ttyname=/dev/pts/1".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION ttytype=tmux-256color".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION lc-messages=C".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION allow-external-password-cache".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-ok=_OK".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-cancel=_Cancel".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-yes=_Yes".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-no=_No".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-prompt=PIN:".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-pwmngr=_Save in password manager".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-cf-visi=Do you really want to make your \
+                                        passphrase visible on the screen?"
+                    .to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-tt-visi=Make passphrase visible".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION default-tt-hide=Hide passphrase".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "OPTION touch-file=/run/user/1000/gnupg/S.gpg-agent".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "GETINFO pid".to_owned(),
+                etalon_output: format!("D {}\nOK", process::id()),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "GETINFO ttyinfo".to_owned(),
+                etalon_output: "D /dev/pts/1 tmux-256color :0\nOK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "GETINFO flavor".to_owned(),
+                etalon_output: "D keyring\nOK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "GETINFO version".to_owned(),
+                etalon_output: format!("D {}\nOK", env!("CARGO_PKG_VERSION")),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "SETPROMPT Passphrase:".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: Some(HashMap::from([
+                    ("-dmenu".to_owned(), None),
+                    ("-display".to_owned(), Some(":0".to_owned())),
+                    ("-input".to_owned(), Some("/dev/null".to_owned())),
+                    ("-password".to_owned(), None),
+                    ("-disable-history".to_owned(), None),
+                    ("-l".to_owned(), Some("0".to_owned())),
+                    ("-p".to_owned(), Some("Passphrase".to_owned())),
+                ])),
+            },
+            AssuanEntry {
+                cmd: "SETDESC Please enter the passphrase for the ssh key%0A  \
+                                ke:yf:in:ge:rp:ri:nt %22<email@yhoo.com>%22"
+                    .to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: Some(HashMap::from([
+                    ("-dmenu".to_owned(), None),
+                    ("-display".to_owned(), Some(":0".to_owned())),
+                    ("-input".to_owned(), Some("/dev/null".to_owned())),
+                    ("-password".to_owned(), None),
+                    ("-disable-history".to_owned(), None),
+                    ("-l".to_owned(), Some("0".to_owned())),
+                    ("-p".to_owned(), Some("Passphrase".to_owned())),
+                    (
+                        "-mesg".to_owned(),
+                        Some(
+                            "Please enter the passphrase for the ssh key\r  \
+                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                                .to_owned(),
+                        ),
+                    ),
+                ])),
+            },
+            AssuanEntry {
+                cmd: "GETPIN".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: Some(HashMap::from([
+                    ("-dmenu".to_owned(), None),
+                    ("-display".to_owned(), Some(":0".to_owned())),
+                    ("-input".to_owned(), Some("/dev/null".to_owned())),
+                    ("-password".to_owned(), None),
+                    ("-disable-history".to_owned(), None),
+                    ("-l".to_owned(), Some("0".to_owned())),
+                    ("-p".to_owned(), Some("Passphrase".to_owned())),
+                    (
+                        "-mesg".to_owned(),
+                        Some(
+                            "Please enter the passphrase for the ssh key\r  \
+                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                                .to_owned(),
+                        ),
+                    ),
+                ])),
+            },
+            AssuanEntry {
+                cmd: "SETERROR Bad Passphrase (try 2 of 3)".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: Some(HashMap::from([
+                    ("-dmenu".to_owned(), None),
+                    ("-display".to_owned(), Some(":0".to_owned())),
+                    ("-input".to_owned(), Some("/dev/null".to_owned())),
+                    ("-password".to_owned(), None),
+                    ("-disable-history".to_owned(), None),
+                    ("-l".to_owned(), Some("0".to_owned())),
+                    ("-p".to_owned(), Some("Passphrase".to_owned())),
+                    (
+                        "-mesg".to_owned(),
+                        Some(
+                            "Bad Passphrase (try 2 of 3)\r***************************\r\
+                             Please enter the passphrase for the ssh key\r  \
+                             ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                                .to_owned(),
+                        ),
+                    ),
+                    ("-window-title".to_owned(), Some("Try 2 of 3".to_owned())),
+                ])),
+            },
+            AssuanEntry {
+                cmd: "SETKEYINFO".to_owned(),
+                etalon_output: "OK".to_owned(),
+                etalon_rofi_args: None,
+            },
+            AssuanEntry {
+                cmd: "BYE".to_owned(),
+                etalon_output: "OK closing connection".to_owned(),
                 etalon_rofi_args: None,
             },
             AssuanEntry {
@@ -415,118 +3434,3811 @@ mod tests {
                 etalon_rofi_args: None,
             },
         ];
-        let mut rofi_args = HashMap::from([
+        let mut rofi_args = HashMap::from([
+            ("-dmenu".to_owned(), None),
+            ("-display".to_owned(), Some(":0".to_owned())),
+            ("-input".to_owned(), Some("/dev/null".to_owned())),
+            ("-password".to_owned(), None),
+            ("-disable-history".to_owned(), None),
+            ("-l".to_owned(), Some("0".to_owned())),
+        ]);
+
+        if let Some(prompt) = custom_prompt {
+            assuan_mapping[19].etalon_rofi_args = Some(HashMap::from([
+                ("-dmenu".to_owned(), None),
+                ("-display".to_owned(), Some(":0".to_owned())),
+                ("-input".to_owned(), Some("/dev/null".to_owned())),
+                ("-password".to_owned(), None),
+                ("-disable-history".to_owned(), None),
+                ("-l".to_owned(), Some("0".to_owned())),
+                ("-p".to_owned(), Some(prompt.to_owned())),
+            ]));
+            assuan_mapping[20].etalon_rofi_args = Some(HashMap::from([
+                ("-dmenu".to_owned(), None),
+                ("-display".to_owned(), Some(":0".to_owned())),
+                ("-input".to_owned(), Some("/dev/null".to_owned())),
+                ("-password".to_owned(), None),
+                ("-disable-history".to_owned(), None),
+                ("-l".to_owned(), Some("0".to_owned())),
+                ("-p".to_owned(), Some(prompt.to_owned())),
+                (
+                    "-mesg".to_owned(),
+                    Some(
+                        "Please enter the passphrase for the ssh key\r  \
+                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                            .to_owned(),
+                    ),
+                ),
+            ]));
+            assuan_mapping[21].etalon_rofi_args = Some(HashMap::from([
+                ("-dmenu".to_owned(), None),
+                ("-display".to_owned(), Some(":0".to_owned())),
+                ("-input".to_owned(), Some("/dev/null".to_owned())),
+                ("-password".to_owned(), None),
+                ("-disable-history".to_owned(), None),
+                ("-l".to_owned(), Some("0".to_owned())),
+                ("-p".to_owned(), Some(prompt.to_owned())),
+                (
+                    "-mesg".to_owned(),
+                    Some(
+                        "Please enter the passphrase for the ssh key\r  \
+                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                            .to_owned(),
+                    ),
+                ),
+            ]));
+            assuan_mapping[22].etalon_rofi_args = Some(HashMap::from([
+                ("-dmenu".to_owned(), None),
+                ("-display".to_owned(), Some(":0".to_owned())),
+                ("-input".to_owned(), Some("/dev/null".to_owned())),
+                ("-password".to_owned(), None),
+                ("-disable-history".to_owned(), None),
+                ("-l".to_owned(), Some("0".to_owned())),
+                ("-p".to_owned(), Some(prompt.to_owned())),
+                (
+                    "-mesg".to_owned(),
+                    Some(
+                        "Bad Passphrase (try 2 of 3)\r***************************\r\
+                            Please enter the passphrase for the ssh key\r  \
+                            ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
+                            .to_owned(),
+                    ),
+                ),
+                ("-window-title".to_owned(), Some("Try 2 of 3".to_owned())),
+            ]));
+            rofi_args.insert("-p".to_owned(), Some(prompt.to_owned()));
+        }
+        (rofi_args, assuan_mapping)
+    }
+
+    /// Serializes the tests that change the process environment, directly or through OPTIONs
+    fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+        static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        ENV.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// OPTION ttyname, ttytype, lc-ctype and lc-messages mirror into these
+    const OPTION_VARS: [&str; 4] = ["GPG_TTY", "GPG_TERM", "LC_CTYPE", "LC_MESSAGES"];
+
+    fn test_args(extra: &[&str]) -> super::RofiArgs {
+        let display: &[&str] = if extra.contains(&"--display") {
+            &[]
+        } else {
+            &["--display", ":0"]
+        };
+        let argv = [&["pinentry-rofi"], display, extra].concat();
+        super::RofiArgs::from_arg_matches(&super::cmd().get_matches_from(argv)).unwrap()
+    }
+
+    fn process_test_handle_command(custom_prompt: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&OPTION_VARS);
+        let (rofi_args, assuan_mapping) = prepare_test_handle_command(custom_prompt);
+        let mut state = super::State {
+            rofi_args,
+            ..Default::default()
+        };
+        // The trailing unknown command must end the session with BYE
+        let args = test_args(&["--exit-on-first-error"]);
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        let mut etalon_output = String::new();
+        for entry in assuan_mapping.iter() {
+            let (action, arg) = entry.cmd.split_once(' ').unwrap_or_else(|| (&entry.cmd, ""));
+            if let Err(e) = super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer) {
+                assert!(format!("{}", e).contains("Unknown assuan command"))
+            }
+
+            etalon_output.push_str(&entry.etalon_output);
+            etalon_output.push('\n');
+
+            if let Some(etalon) = &entry.etalon_rofi_args {
+                assert_eq!(etalon.to_owned(), state.rofi_args, "Action: {action}. Arg: {arg}");
+            }
+        }
+        let output = String::from_utf8(writer.into_inner())?;
+        assert_eq!(etalon_output, output);
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer() -> std::io::Result<()> {
+        let mut writer = super::Writer::new(Vec::new());
+        writer.assuan_send("OK Please go ahead")?;
+        writer.assuan_send("D 1234")?;
+        assert_eq!(writer.into_inner(), b"OK Please go ahead\nD 1234\n");
+        Ok(())
+    }
+
+    /// Output sink shared with [`ScriptedInput`]
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Hands out one command per read and records what had been written by then
+    struct ScriptedInput {
+        commands: Vec<&'static str>,
+        output: SharedOutput,
+        seen: Vec<String>,
+    }
+
+    impl std::io::Read for ScriptedInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.seen
+                .push(String::from_utf8(self.output.0.borrow().clone()).unwrap());
+            if self.commands.is_empty() {
+                return Ok(0);
+            }
+            let line = format!("{}\n", self.commands.remove(0));
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    #[test]
+    fn test_no_flush_per_line() {
+        let args = test_args(&["--no-flush-per-line"]);
+        let output = SharedOutput::default();
+        let mut input = ScriptedInput {
+            commands: vec!["SETDESC Unlock", "GETINFO flavor"],
+            output: output.clone(),
+            seen: Vec::new(),
+        };
+        let mut writer = super::Writer::buffered(std::io::BufWriter::new(output.clone()));
+        super::serve(
+            std::io::BufReader::new(&mut input),
+            &mut super::State::default(),
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(
+            input.seen,
+            [
+                "OK Please go ahead\n",
+                "OK Please go ahead\nOK\n",
+                "OK Please go ahead\nOK\nD keyring\nOK\n",
+            ]
+        );
+    }
+
+    /// Fails its reads with the queued errors before reading from `data`
+    struct FlakyInput {
+        errors: Vec<std::io::ErrorKind>,
+        data: &'static [u8],
+    }
+
+    impl std::io::Read for FlakyInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.errors.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.data.read(buf),
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_connections_lifetime() {
+        for (extra, reply) in [
+            (
+                &[][..],
+                "OK Please go ahead\nD secret\nOK\nD keyring\nOK\nOK closing connection\n",
+            ),
+            (
+                &["--max-connections-lifetime", "3600"][..],
+                "OK Please go ahead\nD secret\nOK\nD keyring\nOK\nOK closing connection\n",
+            ),
+            // The prompt in flight is answered, then nothing more is read
+            (
+                &["--max-connections-lifetime", "0"][..],
+                "OK Please go ahead\nD secret\nOK\n# Warning: --max-connections-lifetime of 0s reached, closing\n",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut writer = super::Writer::new(Vec::new());
+            let mut rofi = MockRofi::with_outputs(vec![rofi_output(0, "secret\n", "")]);
+            super::serve(
+                "GETPIN\nGETINFO flavor\nBYE\n".as_bytes(),
+                &mut super::State::default(),
+                &args,
+                &mut rofi,
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), reply, "{extra:?}");
+        }
+    }
+
+    /// Moves `tick` forward every time it is read
+    #[derive(Debug)]
+    struct FakeClock {
+        start: std::time::Instant,
+        elapsed: Cell<Duration>,
+        tick: Duration,
+    }
+
+    impl FakeClock {
+        fn ticking(tick: Duration) -> Self {
+            FakeClock {
+                start: std::time::Instant::now(),
+                elapsed: Cell::default(),
+                tick,
+            }
+        }
+    }
+
+    impl super::Clock for FakeClock {
+        fn now(&self) -> std::time::Instant {
+            let now = self.start + self.elapsed.get();
+            self.elapsed.set(self.elapsed.get() + self.tick);
+            now
+        }
+    }
+
+    #[test]
+    fn test_flush_timeout() {
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+
+        // Nobody reads the other end, so its buffer fills up and writes stall
+        let (blocked, mut peer) = UnixStream::pair().unwrap();
+        let mut writer = super::Writer::new(super::TimedWriter::new(blocked, Duration::from_millis(50)));
+        let line = "#".repeat(1000);
+        let err = loop {
+            if let Err(err) = writer.assuan_send(&line) {
+                break err;
+            }
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "Assuan output blocked for over 50ms");
+
+        // Once the reader catches up it goes through again
+        peer.set_nonblocking(true).unwrap();
+        let mut drained = vec![0; 1 << 16];
+        while peer.read(&mut drained).is_ok() {}
+        assert!(writer.assuan_send("OK").is_ok());
+        assert!(writer.flush().is_ok());
+    }
+
+    #[test]
+    fn test_listen() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let path = env::temp_dir().join(format!("pinentry-rofi-listen-{}.sock", process::id()));
+        // A socket left behind by an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let socket = super::bind_socket(&path).unwrap();
+
+        let args = test_args(&[]);
+        let base = super::State::default();
+        let mut rofi = MockRofi::with_outputs(vec![rofi_output(0, "Yes\n", ""), rofi_output(0, "OK\n", "")]);
+        for session in ["OPTION default-ok=Yes\nCONFIRM\nBYE\n", "CONFIRM\nBYE\n"] {
+            let client = {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let mut stream = UnixStream::connect(path).unwrap();
+                    stream.write_all(session.as_bytes()).unwrap();
+                    stream.shutdown(std::net::Shutdown::Write).unwrap();
+                    let mut reply = String::new();
+                    stream.read_to_string(&mut reply).unwrap();
+                    reply
+                })
+            };
+            let (stream, _) = socket.listener.accept().unwrap();
+            super::serve_connection(stream, &mut base.for_new_connection(), &args, &mut rofi).unwrap();
+            let reply = client.join().unwrap();
+            assert!(reply.starts_with("OK Please go ahead\n"), "{reply}");
+            assert!(reply.ends_with("OK\nOK closing connection\n"), "{reply}");
+        }
+        // The OPTION stayed with its connection
+        let entries: Vec<_> = rofi.calls.iter().map(|(_, input)| input.as_deref()).collect();
+        assert_eq!(entries, [Some("Yes\nCancel"), Some("OK\nCancel")]);
+
+        // Removed once done with
+        drop(socket);
+        assert!(!path.exists());
+        // Any other file in the way is left alone
+        std::fs::write(&path, "").unwrap();
+        assert!(super::bind_socket(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_max_connections_lifetime_fake_clock() {
+        // Start, then one reading after each command: 0s, 25s, 50s, 75s
+        let args = test_args(&["--max-connections-lifetime", "60"]);
+        let mut state = super::State {
+            clock: Box::new(FakeClock::ticking(Duration::from_secs(25))),
+            ..Default::default()
+        };
+        let mut writer = super::Writer::new(Vec::new());
+        super::serve(
+            "GETINFO flavor\nGETINFO flavor\nGETINFO flavor\nGETINFO flavor\n".as_bytes(),
+            &mut state,
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK Please go ahead\n".to_owned()
+                + &"D keyring\nOK\n".repeat(3)
+                + "# Warning: --max-connections-lifetime of 60s reached, closing\n"
+        );
+    }
+
+    #[test]
+    fn test_serve_read_errors() {
+        let args = test_args(&[]);
+        let serve = |errors| {
+            let mut writer = super::Writer::new(Vec::new());
+            let input = FlakyInput {
+                errors,
+                data: b"GETINFO flavor\r\nBYE\n",
+            };
+            let result = super::serve(
+                std::io::BufReader::new(input),
+                &mut super::State::default(),
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            );
+            (result.is_ok(), String::from_utf8(writer.into_inner()).unwrap())
+        };
+
+        let (ok, output) = serve(vec![std::io::ErrorKind::Interrupted; 3]);
+        assert!(ok);
+        assert_eq!(output, "OK Please go ahead\nD keyring\nOK\nOK closing connection\n");
+
+        let (ok, output) = serve(vec![std::io::ErrorKind::BrokenPipe]);
+        assert!(!ok);
+        assert!(
+            output.starts_with("OK Please go ahead\nERR 83886350 Read error <"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn test_hooks_around_prompt() -> Result<(), Box<dyn std::error::Error>> {
+        let trace = env::temp_dir().join(format!("pinentry-rofi-hooks-{}", process::id()));
+        let trace_path = trace.to_str().unwrap();
+        let args = test_args(&[
+            "--pre-cmd",
+            &format!("echo pre >> {trace_path}"),
+            "--post-cmd",
+            &format!("echo post >> {trace_path}; exit 1"),
+        ]);
+        let result = super::with_hooks(&args, || {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&trace)
+                .and_then(|mut f| std::io::Write::write_all(&mut f, b"prompt\n"))
+        });
+        let content = std::fs::read_to_string(&trace)?;
+        std::fs::remove_file(&trace)?;
+        result?;
+        // A failing post-cmd is not fatal
+        assert_eq!(content, "pre\nprompt\npost\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify() {
+        assert!(super::notify(&None).is_none());
+
+        let trace = env::temp_dir().join(format!("pinentry-rofi-notify-{}", process::id()));
+        let command = format!("echo notified > {}", trace.to_str().unwrap());
+        super::notify(&Some(command)).unwrap().join().unwrap();
+        assert_eq!(std::fs::read_to_string(&trace).unwrap(), "notified\n");
+        std::fs::remove_file(&trace).unwrap();
+
+        // A failing command is only logged
+        let capture = LogCapture::any_thread();
+        super::notify(&Some("exit 3".to_owned())).unwrap().join().unwrap();
+        assert!(capture
+            .lines()
+            .iter()
+            .any(|line| line.contains("notify `exit 3` failed")));
+
+        // The prompt still comes up
+        let (output, rofi) = run_getpin_session(
+            &["--notify", "exit 1"],
+            &[],
+            MockRofi::with_outputs(vec![rofi_output(0, "secret\n", "")]),
+        );
+        assert_eq!(output, "D secret\nOK\n");
+        assert_eq!(rofi.calls.len(), 1);
+    }
+
+    #[test]
+    fn test_option_value_with_equals() {
+        let args = test_args(&["--no-env-options"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for option in [
+            "default-ok=a=b=c",
+            "default-cancel=dGVzdA==",
+            "default-prompt==leading",
+            "default-tt-visi=%3D=%3D",
+            "ttyname=/dev/pts/1=x",
+            "no-value",
+        ] {
+            super::handle_command(
+                "OPTION",
+                option,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+        }
+        for (name, value) in [
+            ("default-ok", "a=b=c"),
+            ("default-cancel", "dGVzdA=="),
+            ("default-prompt", "=leading"),
+            ("default-tt-visi", "==="),
+            ("ttyname", "/dev/pts/1=x"),
+            ("no-value", ""),
+        ] {
+            assert_eq!(state.options[name], value, "{name}");
+        }
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\n".repeat(6));
+    }
+
+    #[test]
+    fn test_truncate_desc() {
+        use super::truncate_desc;
+
+        assert_eq!(truncate_desc("abcdef", 6), "abcdef");
+        assert_eq!(truncate_desc("abcdefg", 6), "abcdef…");
+        assert_eq!(truncate_desc("", 0), "");
+        assert_eq!(truncate_desc("a", 0), "…");
+        // Never split a multibyte character
+        assert_eq!(truncate_desc("abcdeф", 6), "abcde…");
+        // Never split a percent escape
+        assert_eq!(truncate_desc("abcd%0Aef", 6), "abcd…");
+        assert_eq!(truncate_desc("abcd%0Aef", 5), "abcd…");
+        assert_eq!(truncate_desc("abcd%0Aef", 7), "abcd%0A…");
+        // The escape check looks back into a multibyte character
+        assert_eq!(truncate_desc("xxфbc", 5), "xxфb…");
+        assert_eq!(truncate_desc("фф%0A", 5), "фф…");
+    }
+
+    #[test]
+    fn test_desc_paragraphs() {
+        for (desc, mesg) in [
+            (
+                "Please enter the passphrase to unlock the key:%0A%0A%22Alice <alice@example.org>%22%0A  ID 0x1234",
+                "Please enter the passphrase to unlock the key:\r\r&quot;Alice &lt;alice@example.org&gt;&quot;\r  ID 0x1234",
+            ),
+            ("One%0A%0A%0A%0ATwo%0A", "One\r\rTwo"),
+            ("One%0A  %0A%09%0ATwo", "One\r\rTwo"),
+            ("%0A%0AOne%0D%0A%0D%0ATwo%0A%0A", "One\r\rTwo"),
+            ("", ""),
+        ] {
+            assert_eq!(super::desc_to_mesg(desc, None, super::DescMarkup::Escape), mesg, "{desc}");
+        }
+    }
+
+    #[test]
+    fn test_desc_max_lines() {
+        let desc = "One%0ATwo%0A%0AThree";
+        for (max_lines, mesg) in [
+            (None, "One\rTwo\r\rThree"),
+            (Some(4), "One\rTwo\r\rThree"),
+            (Some(5), "One\rTwo\r\rThree"),
+            (Some(2), "One\rTwo…"),
+            // Never end on the blank line between paragraphs
+            (Some(3), "One\rTwo…"),
+            (Some(1), "One…"),
+            (Some(0), "…"),
+        ] {
+            assert_eq!(
+                super::desc_to_mesg(desc, max_lines, super::DescMarkup::Escape),
+                mesg,
+                "{max_lines:?}"
+            );
+        }
+
+        let args = test_args(&["--desc-max-lines", "1"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "SETDESC",
+            desc,
+            &mut state,
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(state.rofi_args["-mesg"], Some("One…".to_owned()));
+    }
+
+    #[test]
+    fn test_safe_markup() {
+        use super::safe_markup;
+
+        // Allowed
+        assert_eq!(
+            safe_markup("<b>Bold</b> and <i>italic</i>"),
+            "<b>Bold</b> and <i>italic</i>"
+        );
+        assert_eq!(
+            safe_markup("<span foreground=\"#ff0000\">red</span> <span foreground=\"blue\">blue</span>"),
+            "<span foreground=\"#ff0000\">red</span> <span foreground=\"blue\">blue</span>"
+        );
+        assert_eq!(safe_markup("<b><i>both</i></b>"), "<b><i>both</i></b>");
+        // Stripped of meaning
+        assert_eq!(
+            safe_markup("\"Alice <alice@example.org>\""),
+            "&quot;Alice &lt;alice@example.org&gt;&quot;"
+        );
+        assert_eq!(safe_markup("<u>under</u>"), "&lt;u&gt;under&lt;/u&gt;");
+        assert_eq!(
+            safe_markup("<span background=\"red\">x</span>"),
+            "&lt;span background=&quot;red&quot;&gt;x&lt;/span&gt;"
+        );
+        assert_eq!(
+            safe_markup("<span foreground=\"red\" size=\"9999\">x</span>"),
+            "&lt;span foreground=&quot;red&quot; size=&quot;9999&quot;&gt;x&lt;/span&gt;"
+        );
+        assert_eq!(
+            safe_markup("<span foreground=\"r;d\">x</span>"),
+            "&lt;span foreground=&quot;r;d&quot;&gt;x&lt;/span&gt;"
+        );
+        assert_eq!(safe_markup("a < b & c"), "a &lt; b &amp; c");
+        // Kept balanced
+        assert_eq!(safe_markup("</b>x"), "&lt;/b&gt;x");
+        assert_eq!(safe_markup("<b><i>x</b></i>"), "<b><i>x&lt;/b&gt;</i></b>");
+        assert_eq!(safe_markup("<b>open\nline"), "<b>open\rline</b>");
+
+        let args = test_args(&["--desc-markup", "safe"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "SETDESC",
+            "Unlock <b>work</b> key <alice@example.org>",
+            &mut state,
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(
+            state.rofi_args["-mesg"],
+            Some("Unlock <b>work</b> key &lt;alice@example.org&gt;".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_setdesc_max_len() -> Result<(), Box<dyn std::error::Error>> {
+        let args = test_args(&["--max-desc-len", "10"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (desc, mesg) in [
+            ("0123456789", "0123456789"),
+            ("0123456789<b>", "0123456789…"),
+            ("01234567%3C", "01234567…"),
+        ] {
+            super::handle_command(
+                "SETDESC",
+                desc,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )?;
+            assert_eq!(state.rofi_args["-mesg"], Some(mesg.to_owned()));
+        }
+        assert_eq!(String::from_utf8(writer.into_inner())?, "OK\nOK\nOK\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_desc_prefix() {
+        for (extra, desc, mesg) in [
+            (&[][..], "Unlock%0Athe key", "Unlock\rthe key"),
+            (
+                &["--desc-prefix", "On <laptop> & co"][..],
+                "Unlock%0Athe key",
+                "On &lt;laptop&gt; &amp; co\r\rUnlock\rthe key",
+            ),
+            (
+                &["--desc-prefix", "On <laptop>", "--desc-markup", "safe"][..],
+                "<b>Unlock</b>",
+                "On &lt;laptop&gt;\r\r<b>Unlock</b>",
+            ),
+            (&["--desc-prefix", "Host: a"][..], "", "Host: a"),
+            // The prefix's 7 bytes leave 3 of the 10 for the description
+            (
+                &["--desc-prefix", "Host: a", "--max-desc-len", "10"][..],
+                "0123456789",
+                "Host: a\r\r012…",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "SETDESC",
+                desc,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(state.rofi_args["-mesg"].as_deref(), Some(mesg), "{extra:?}");
+        }
+    }
+
+    fn run_confirm_session(arg: &str, rofi: MockRofi) -> (String, MockRofi) {
+        run_confirm_session_with_args(arg, rofi, &[], &[])
+    }
+
+    fn run_confirm_session_with_args(
+        arg: &str,
+        mut rofi: MockRofi,
+        extra: &[&str],
+        options: &[&str],
+    ) -> (String, MockRofi) {
+        let args = test_args(extra);
+        let mut state = super::State {
+            rofi_args: HashMap::from([
+                ("-dmenu".to_owned(), None),
+                ("-input".to_owned(), Some("/dev/null".to_owned())),
+                ("-password".to_owned(), None),
+                ("-l".to_owned(), Some("0".to_owned())),
+            ]),
+            ..Default::default()
+        };
+        let mut writer = super::Writer::new(Vec::new());
+        for option in options {
+            super::handle_command("OPTION", option, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        super::handle_command("CONFIRM", arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        (String::from_utf8(writer.into_inner()).unwrap(), rofi)
+    }
+
+    #[test]
+    fn test_parse_confirm_flags() {
+        use super::{parse_confirm_flags, ConfirmFlags};
+
+        assert_eq!(parse_confirm_flags(""), Ok(ConfirmFlags { one_button: false }));
+        assert_eq!(
+            parse_confirm_flags("--one-button"),
+            Ok(ConfirmFlags { one_button: true })
+        );
+        assert_eq!(
+            parse_confirm_flags(" --one-button "),
+            Ok(ConfirmFlags { one_button: true })
+        );
+        assert_eq!(parse_confirm_flags("--two-buttons"), Err("--two-buttons".to_owned()));
+        assert_eq!(parse_confirm_flags("--one-button yes"), Err("yes".to_owned()));
+    }
+
+    #[test]
+    fn test_message_then_confirm() {
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::with_outputs(vec![
+            rofi_output(0, "OK\n", ""),
+            rofi_output(0, "OK\n", ""),
+            rofi_output(0, "OK\n", ""),
+            rofi_output(0, "OK\n", ""),
+        ]);
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [
+            ("SETERROR", "Bad"),
+            ("SETDESC", "First"),
+            ("MESSAGE", ""),
+            ("SETDESC", "Second"),
+            ("CONFIRM", ""),
+            ("SETDESC", "Third"),
+            ("MESSAGE", ""),
+            ("CONFIRM", ""),
+        ] {
+            super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\n".repeat(8));
+
+        let mesgs: Vec<_> = rofi.calls.iter().map(|(args, _)| arg_value(args, "-mesg")).collect();
+        assert_eq!(
+            mesgs,
+            [
+                Some("Bad\r***************************\rFirst"),
+                Some("Second"),
+                Some("Third"),
+                None
+            ]
+        );
+        // MESSAGE has a single button; CONFIRM keeps both
+        let entries: Vec<_> = rofi.calls.iter().map(|(_, input)| input.as_deref()).collect();
+        assert_eq!(
+            entries,
+            [Some("OK"), Some("OK\nCancel"), Some("OK"), Some("OK\nCancel")]
+        );
+    }
+
+    #[test]
+    fn test_confirm() {
+        let (output, rofi) = run_confirm_session("", MockRofi::with_outputs(vec![rofi_output(0, "OK\n", "")]));
+        assert_eq!(output, "OK\n");
+        let (args, input) = &rofi.calls[0];
+        assert_eq!(input.as_deref(), Some("OK\nCancel"));
+        assert!(args.contains(&"-no-custom".to_owned()));
+        assert!(!args.contains(&"-password".to_owned()));
+        assert!(!args.contains(&"-input".to_owned()));
+        let l = args.iter().position(|arg| arg == "-l").unwrap();
+        assert_eq!(args[l + 1], "2");
+
+        let (output, _) = run_confirm_session("", MockRofi::with_outputs(vec![rofi_output(0, "Cancel\n", "")]));
+        assert_eq!(output, "ERR 83886194 Not confirmed\n");
+
+        let (output, _) = run_confirm_session("", MockRofi::with_outputs(vec![rofi_output(1, "", "")]));
+        assert_eq!(output, "ERR 83886179 Operation cancelled <rofi>\n");
+    }
+
+    #[test]
+    fn test_confirm_on_empty() {
+        for (extra, reply) in [
+            (&[][..], "ERR 83886179 Operation cancelled <rofi>\n"),
+            (
+                &["--confirm-on-empty", "cancel"][..],
+                "ERR 83886179 Operation cancelled <rofi>\n",
+            ),
+            (
+                &["--confirm-on-empty", "not-confirmed"][..],
+                "ERR 83886194 Not confirmed\n",
+            ),
+        ] {
+            for stdout in ["", "\n", "OK Cancel\n", "ok\n"] {
+                let rofi = MockRofi::with_outputs(vec![rofi_output(0, stdout, "")]);
+                let (output, _) = run_confirm_session_with_args("", rofi, extra, &[]);
+                assert_eq!(output, reply, "{extra:?} {stdout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_confirm() {
+        for (extra, arg, reply) in [
+            (&["--auto-confirm", "yes"][..], "", "OK\n"),
+            (&["--auto-confirm", "yes"][..], "--one-button", "OK\n"),
+            (&["--auto-confirm", "no"][..], "", "ERR 83886194 Not confirmed\n"),
+            (&["--auto-confirm", "no"][..], "--one-button", "OK\n"),
+        ] {
+            let (output, rofi) = run_confirm_session_with_args(arg, MockRofi::default(), extra, &[]);
+            assert_eq!(output, reply, "{extra:?} {arg:?}");
+            assert!(rofi.calls.is_empty());
+        }
+        for mode in ["yes", "no"] {
+            let mut rofi = MockRofi::default();
+            let output = crate::testing::run_session(&["--auto-confirm", mode], "MESSAGE\n", &mut rofi).unwrap();
+            assert_eq!(output, "OK Please go ahead\nOK\n", "{mode}");
+            assert!(rofi.calls.is_empty());
+        }
+
+        for extra in [&[][..], &["--auto-confirm", "never"][..]] {
+            let rofi = MockRofi::with_outputs(vec![rofi_output(0, "Cancel\n", "")]);
+            let (output, rofi) = run_confirm_session_with_args("", rofi, extra, &[]);
+            assert_eq!(output, "ERR 83886194 Not confirmed\n");
+            assert_eq!(rofi.calls.len(), 1);
+        }
+
+        // GETPIN still asks
+        let rofi = MockRofi::with_outputs(vec![rofi_output(0, "secret\n", "")]);
+        let (output, rofi) = run_getpin_session(&["--auto-confirm", "yes"], &[], rofi);
+        assert_eq!(output, "D secret\nOK\n");
+        assert_eq!(rofi.calls.len(), 1);
+    }
+
+    #[test]
+    fn test_confirm_default_choice() {
+        // gpg-agent always sends both labels; they leave rofi's own selection alone
+        for (options, arg, selected) in [
+            (&[][..], "", None),
+            (&["default-yes=_Yes"][..], "", None),
+            (&["default-no=_No"][..], "", None),
+            (&["default-yes=_Yes", "default-no=_No"][..], "", None),
+            (&["default-no=_No"][..], "--one-button", None),
+        ] {
+            let (_, rofi) = run_confirm_session_with_args(arg, MockRofi::default(), &[], options);
+            assert_eq!(
+                arg_value(&rofi.calls[0].0, "-selected-row"),
+                selected,
+                "{options:?} {arg}"
+            );
+        }
+
+        // --confirm-default-focus picks the row, whatever the labels
+        for (focus, options, arg, selected) in [
+            ("safe", &[][..], "", "1"),
+            ("safe", &["default-yes=_Yes", "default-no=_No"][..], "", "1"),
+            ("safe", &[][..], "--one-button", "0"),
+            ("affirmative", &[][..], "", "0"),
+            ("affirmative", &["default-yes=_Yes", "default-no=_No"][..], "", "0"),
+            ("affirmative", &[][..], "--one-button", "0"),
+        ] {
+            let extra = ["--confirm-default-focus", focus];
+            let (_, rofi) = run_confirm_session_with_args(arg, MockRofi::default(), &extra, options);
+            assert_eq!(
+                arg_value(&rofi.calls[0].0, "-selected-row"),
+                Some(selected),
+                "{focus} {options:?} {arg}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_confirm_status_line() {
+        for (extra, code, stdout, reply) in [
+            (&[][..], 0, "OK\n", "OK\n"),
+            (&[][..], 0, "Cancel\n", "ERR 83886194 Not confirmed\n"),
+            (&["--confirm-status-line"][..], 0, "OK\n", "S CONFIRM_OK\nOK\n"),
+            (
+                &["--confirm-status-line"][..],
+                0,
+                "Cancel\n",
+                "S CONFIRM_CANCEL\nERR 83886194 Not confirmed\n",
+            ),
+            // No button picked
+            (
+                &["--confirm-status-line"][..],
+                1,
+                "",
+                "ERR 83886179 Operation cancelled <rofi>\n",
+            ),
+        ] {
+            let rofi = MockRofi::with_outputs(vec![rofi_output(code, stdout, "")]);
+            let (output, _) = run_confirm_session_with_args("", rofi, extra, &[]);
+            assert_eq!(output, reply, "{extra:?} {stdout:?}");
+        }
+    }
+
+    #[test]
+    fn test_confirm_one_button() {
+        for (code, stdout) in [(0, "OK\n"), (1, "")] {
+            let (output, rofi) = run_confirm_session(
+                "--one-button",
+                MockRofi::with_outputs(vec![rofi_output(code, stdout, "")]),
+            );
+            assert_eq!(output, "OK\n");
+            let (args, input) = &rofi.calls[0];
+            assert_eq!(input.as_deref(), Some("OK"));
+            let l = args.iter().position(|arg| arg == "-l").unwrap();
+            assert_eq!(args[l + 1], "1");
+        }
+
+        // rofi failing or getting killed is no acknowledgement
+        let mut killed = rofi_output(0, "", "");
+        killed.status = process::ExitStatus::from_raw(libc::SIGKILL);
+        for (failed, reply) in [
+            (
+                rofi_output(2, "", "Cannot open display"),
+                "ERR 83886179 Operation cancelled <Cannot open display>\n",
+            ),
+            (killed, "ERR 83886081 General error <rofi terminated by signal 9>\n"),
+        ] {
+            let (output, _) = run_confirm_session("--one-button", MockRofi::with_outputs(vec![failed]));
+            assert_eq!(output, reply);
+        }
+    }
+
+    #[test]
+    fn test_option_percent_decoded() {
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for option in [
+            "default-cf-visi=Do you really want to make your passphrase visible%3F%0AReally%25",
+            "default-ok=_OK",
+            "default-pwmngr=Save in password _manager%25",
+            "touch-file=/run/user/1000/gnupg/S.gpg-agent",
+            "grab",
+            "bad-escape=%E2%82",
+        ] {
+            super::handle_command(
+                "OPTION",
+                option,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            state.options["default-cf-visi"],
+            "Do you really want to make your passphrase visible?\nReally%"
+        );
+        assert_eq!(state.options["default-ok"], "_OK");
+        assert_eq!(state.label("default-pwmngr", "Save"), "Save in password manager%");
+        assert_eq!(state.options["touch-file"], "/run/user/1000/gnupg/S.gpg-agent");
+        assert_eq!(state.options["grab"], "");
+        // Values that don't decode to UTF-8 are kept raw
+        assert_eq!(state.options["bad-escape"], "%E2%82");
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\n".repeat(6));
+    }
+
+    #[test]
+    fn test_confirm_labels_from_options() {
+        let (output, rofi) = run_confirm_session_with_args(
+            "",
+            MockRofi::with_outputs(vec![rofi_output(0, "Ja, gerne\n", "")]),
+            &[],
+            &["default-ok=_Ja%2C gerne", "default-cancel=Ab__brechen"],
+        );
+        assert_eq!(rofi.calls[0].1.as_deref(), Some("Ja, gerne\nAb_brechen"));
+        assert!(output.ends_with("OK\nOK\nOK\n"), "{output}");
+    }
+
+    #[test]
+    fn test_seterror_markup() {
+        let sep = "\r***************************\r";
+        for (extra, error) in [
+            // Without the option the error is passed through as-is
+            (vec![], "<b>Bad</b> %26 worse"),
+            (vec!["--no-markup-in-error"], "&lt;b&gt;Bad&lt;/b&gt; &amp; worse"),
+        ] {
+            let args = test_args(&extra);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [("SETDESC", "a <desc>"), ("SETERROR", "<b>Bad</b> %26 worse")] {
+                super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+            }
+            assert_eq!(state.rofi_args["-mesg"], Some(format!("{error}{sep}a &lt;desc&gt;")));
+        }
+    }
+
+    #[test]
+    fn test_seterror_ordering() {
+        let sep = "\r***************************\r";
+        for (commands, mesg) in [
+            // The error waits for the description
+            (
+                &[("SETERROR", "Bad Passphrase"), ("SETDESC", "Unlock")][..],
+                Some(format!("Bad Passphrase{sep}Unlock")),
+            ),
+            (&[("SETERROR", "Bad Passphrase")][..], Some("Bad Passphrase".to_owned())),
+            // A second error replaces the first instead of stacking
+            (
+                &[
+                    ("SETDESC", "Unlock"),
+                    ("SETERROR", "try 1"),
+                    ("SETERROR", "try 2"),
+                    ("SETERROR", "try 3"),
+                ][..],
+                Some(format!("try 3{sep}Unlock")),
+            ),
+            // Nor is the description mistaken for an earlier error when it holds a separator line
+            (
+                &[
+                    ("SETDESC", "Unlock%0A***************************%0Akey"),
+                    ("SETERROR", "try 1"),
+                    ("SETERROR", "try 2"),
+                    ("SETERROR", "try 3"),
+                ][..],
+                Some(format!("try 3{sep}Unlock{sep}key")),
+            ),
+            // A new description keeps the error
+            (
+                &[
+                    ("SETDESC", "Unlock"),
+                    ("SETERROR", "Bad Passphrase"),
+                    ("SETDESC", "Unlock again"),
+                ][..],
+                Some(format!("Bad Passphrase{sep}Unlock again")),
+            ),
+            // An empty error clears it
+            (
+                &[("SETDESC", "Unlock"), ("SETERROR", "Bad"), ("SETERROR", "")][..],
+                Some("Unlock".to_owned()),
+            ),
+            (&[("SETERROR", "Bad"), ("SETERROR", "")][..], None),
+        ] {
+            let args = test_args(&[]);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in commands {
+                super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+            }
+            assert_eq!(state.rofi_args.get("-mesg").cloned().flatten(), mesg, "{commands:?}");
+        }
+    }
+
+    #[test]
+    fn test_getpin_clears_error() {
+        let sep = "\r***************************\r";
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::with_outputs(vec![
+            rofi_output(1, "", ""),
+            rofi_output(0, "wrong\n", ""),
+            rofi_output(0, "right\n", ""),
+        ]);
+        let mut writer = super::Writer::new(Vec::new());
+        let mut send = |state: &mut super::State, action, arg| {
+            super::handle_command(action, arg, state, &args, &mut rofi, &mut writer).unwrap()
+        };
+        let mesg = |state: &super::State| state.rofi_args.get("-mesg").cloned().flatten();
+
+        send(&mut state, "SETDESC", "Unlock");
+        send(&mut state, "SETERROR", "Bad Passphrase (try 2 of 3)");
+        // Cancelled: the error stays for the retry
+        send(&mut state, "GETPIN", "");
+        assert_eq!(mesg(&state), Some(format!("Bad Passphrase (try 2 of 3){sep}Unlock")));
+        assert_eq!(state.rofi_args["-window-title"], Some("Try 2 of 3".to_owned()));
+        // Answered: only the description is left
+        send(&mut state, "GETPIN", "");
+        assert_eq!(mesg(&state), Some("Unlock".to_owned()));
+        assert!(!state.rofi_args.contains_key("-window-title"));
+        // Until gpg-agent reports the next failure
+        send(&mut state, "SETERROR", "Bad Passphrase (try 3 of 3)");
+        assert_eq!(mesg(&state), Some(format!("Bad Passphrase (try 3 of 3){sep}Unlock")));
+        send(&mut state, "GETPIN", "");
+        assert_eq!(mesg(&state), Some("Unlock".to_owned()));
+
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nOK\nERR 83886179 Operation cancelled <rofi>\nD wrong\nOK\nOK\nD right\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_retry_window_title() {
+        assert_eq!(super::parse_retry("Bad Passphrase (try 2 of 3)"), Some((2, 3)));
+        assert_eq!(super::parse_retry("Bad PIN (try 10 of 10)"), Some((10, 10)));
+        assert_eq!(super::parse_retry("Bad Passphrase"), None);
+        assert_eq!(super::parse_retry("try again of course"), None);
+
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        let mut send = |state: &mut super::State, action, arg| {
+            super::handle_command(action, arg, state, &args, &mut MockRofi::default(), &mut writer).unwrap()
+        };
+        send(&mut state, "SETERROR", "Bad Passphrase (try 2 of 3)");
+        assert_eq!(state.rofi_args["-window-title"].as_deref(), Some("Try 2 of 3"));
+
+        send(&mut state, "SETDESC", "Unlock key");
+        send(&mut state, "SETTITLE", "Unlock%20OpenPGP");
+        send(&mut state, "SETERROR", "Bad Passphrase (try 3 of 3)");
+        assert_eq!(
+            state.rofi_args["-window-title"].as_deref(),
+            Some("Unlock OpenPGP (try 3 of 3)")
+        );
+        assert_eq!(
+            state.rofi_args["-mesg"].as_deref(),
+            Some("Bad Passphrase (try 3 of 3)\r***************************\rUnlock key")
+        );
+
+        send(&mut state, "SETERROR", "No smartcard");
+        assert_eq!(state.rofi_args["-window-title"].as_deref(), Some("Unlock OpenPGP"));
+    }
+
+    #[test]
+    fn test_title_reset() {
+        let title_session = |extra: &[&str], commands: &[(&str, &str)]| {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in commands {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            let titles: Vec<_> = rofi
+                .calls
+                .iter()
+                .map(|(args, _)| arg_value(args, "-window-title").map(str::to_owned))
+                .collect();
+            (String::from_utf8(writer.into_inner()).unwrap(), state, titles)
+        };
+
+        // RESET forgets the title along with the rest of the dialog, and keeps the OPTIONs
+        let (output, state, titles) = title_session(
+            &["--no-env-options"],
+            &[
+                ("OPTION", "lc-messages=de_DE"),
+                ("SETTITLE", "Unlock%20OpenPGP"),
+                ("SETDESC", "First"),
+                ("SETKEYINFO", "n/ABCD"),
+                ("SETERROR", "Bad Passphrase (try 2 of 3)"),
+                ("GETPIN", ""),
+                ("RESET", ""),
+                ("SETDESC", "Second"),
+                ("GETPIN", ""),
+            ],
+        );
+        assert_eq!(output, "OK\nOK\nOK\nOK\nOK\nOK\nOK\nOK\nOK\n");
+        assert_eq!(titles, [Some("Unlock OpenPGP (try 2 of 3)".to_owned()), None]);
+        assert_eq!(
+            state.rofi_args.get("-mesg").cloned().flatten().as_deref(),
+            Some("Second")
+        );
+        assert_eq!(state.keygrip, None);
+        assert_eq!(state.keyinfo_context, None);
+        assert_eq!(state.lc_messages(), Some("de_DE"));
+
+        let dialogs = [
+            ("SETTITLE", "Unlock"),
+            ("SETKEYINFO", "n/ABCD"),
+            ("SETDESC", "Key%20A"),
+            ("GETPIN", ""),
+            // A retry for the same key
+            ("SETERROR", "Bad Passphrase (try 2 of 3)"),
+            ("SETKEYINFO", "n/ABCD"),
+            ("SETDESC", "Key%20A"),
+            ("GETPIN", ""),
+            // Another key
+            ("SETKEYINFO", "n/EF01"),
+            ("SETDESC", "Key%20B"),
+            ("GETPIN", ""),
+        ];
+        // By default the title stays for the whole connection
+        let (_, _, titles) = title_session(&[], &dialogs);
+        assert_eq!(
+            titles,
+            [
+                Some("Unlock".to_owned()),
+                Some("Unlock (try 2 of 3)".to_owned()),
+                Some("Unlock".to_owned())
+            ]
+        );
+        let (_, _, titles) = title_session(&["--title-per-dialog"], &dialogs);
+        assert_eq!(
+            titles,
+            [Some("Unlock".to_owned()), Some("Unlock (try 2 of 3)".to_owned()), None]
+        );
+
+        // A title set for the next dialog survives its own SETDESC
+        let (_, _, titles) = title_session(
+            &["--title-per-dialog"],
+            &[
+                ("SETDESC", "First"),
+                ("GETPIN", ""),
+                ("SETTITLE", "Second"),
+                ("SETDESC", "Second"),
+                ("GETPIN", ""),
+            ],
+        );
+        assert_eq!(titles, [None, Some("Second".to_owned())]);
+    }
+
+    #[test]
+    fn test_confirm_matching() {
+        let (_, rofi) = run_confirm_session("", MockRofi::default());
+        assert!(!rofi.calls[0].0.contains(&"-matching".to_owned()));
+
+        for mode in ["normal", "fuzzy", "regex"] {
+            let (_, rofi) = run_confirm_session_with_args("", MockRofi::default(), &["--matching", mode], &[]);
+            let args = &rofi.calls[0].0;
+            let matching = args.iter().position(|arg| arg == "-matching").unwrap();
+            assert_eq!(args[matching + 1], mode);
+        }
+    }
+
+    #[test]
+    fn test_confirm_malformed_flags() {
+        for arg in ["--one", "-one-button", "--one-button now"] {
+            let (output, rofi) = run_confirm_session(arg, MockRofi::default());
+            assert!(output.starts_with("ERR 83886360 "), "{output}");
+            assert!(rofi.calls.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_display_available() {
+        use super::display_available;
+
+        assert!(display_available(":12345", Some("wayland-1")));
+        assert!(!display_available(":12345", None));
+        assert!(!display_available(":12345.0", Some("")));
+        assert!(!display_available("", None));
+        assert!(display_available("remote:0", None));
+    }
+
+    #[test]
+    fn test_resolves_to_self() -> Result<(), Box<dyn std::error::Error>> {
+        use std::{ffi::OsStr, fs, os::unix::fs::symlink};
+
+        let dir = env::temp_dir().join(format!("pinentry-rofi-self-{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let self_exe = dir.join("pinentry-rofi");
+        fs::write(&self_exe, "")?;
+        symlink(&self_exe, dir.join("rofi"))?;
+        fs::write(dir.join("real-rofi"), "")?;
+        let path = dir.as_os_str();
+
+        let checks = [
+            super::resolves_to_self("rofi", Some(path), &self_exe),
+            super::resolves_to_self(dir.join("rofi").to_str().unwrap(), None, &self_exe),
+            super::resolves_to_self("real-rofi", Some(path), &self_exe),
+            super::resolves_to_self("rofi", Some(OsStr::new("/nonexistent")), &self_exe),
+            super::resolves_to_self("missing", Some(path), &self_exe),
+        ];
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(checks, [true, true, false, false, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_guard() {
+        let _lock = lock_env();
+        let (set, unset) = ("PINENTRY_ROFI_TEST_SET", "PINENTRY_ROFI_TEST_UNSET");
+        env::set_var(set, "/dev/pts/1");
+        env::remove_var(unset);
+        {
+            let _env = super::EnvGuard::capture(&[set, unset]);
+            env::set_var(set, "/dev/pts/7");
+            env::set_var(unset, "de_DE.UTF-8");
+        }
+        assert_eq!(env::var(set).as_deref(), Ok("/dev/pts/1"));
+        assert!(env::var_os(unset).is_none());
+        env::remove_var(set);
+    }
+
+    #[test]
+    fn test_pidfile() -> Result<(), Box<dyn std::error::Error>> {
+        use std::{fs, path::Path};
+
+        let dir = env::temp_dir().join(format!("pinentry-rofi-pidfile-{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("pinentry-rofi.pid");
+        let own_pid = process::id() as libc::pid_t;
+        let self_exe = Path::new("/proc/self/exe");
+
+        assert_eq!(super::read_pidfile(&path), None);
+        let pidfile = super::PidFile::create(&path)?;
+        assert_eq!(fs::read_to_string(&path)?, format!("{own_pid}\n"));
+        assert_eq!(super::read_pidfile(&path), Some(own_pid));
+        // Never ourselves
+        assert_eq!(super::kill_existing(&path, self_exe), None);
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // A pid running something else is not touched, and a newer owner's file is kept
+        let mut other = process::Command::new("sleep").arg("10").spawn()?;
+        let pidfile = super::PidFile::create(&path)?;
+        fs::write(&path, format!("{}\n", other.id()))?;
+        assert_eq!(super::kill_existing(&path, self_exe), None);
+        assert_eq!(other.try_wait()?, None);
+        drop(pidfile);
+        assert_eq!(super::read_pidfile(&path), Some(other.id() as libc::pid_t));
+
+        // The same executable is terminated
+        let sleep = fs::canonicalize(Path::new("/proc").join(other.id().to_string()).join("exe"))?;
+        assert!(!super::stale_pidfile(&path));
+        assert_eq!(super::kill_existing(&path, &sleep), Some(other.id() as libc::pid_t));
+        assert_eq!(other.wait()?.signal(), Some(libc::SIGTERM));
+
+        // Gone now that it was reaped, as is anything that isn't a pid
+        assert!(super::stale_pidfile(&path));
+        for content in ["", "not a pid\n", "-1\n"] {
+            fs::write(&path, content)?;
+            assert!(super::stale_pidfile(&path), "{content:?}");
+        }
+        fs::remove_file(&path)?;
+        assert!(!super::stale_pidfile(&path));
+        drop(super::PidFile::create(&path)?);
+        assert!(!path.exists());
+
+        // A planted symlink is replaced, not written through
+        let target = dir.join("target");
+        fs::write(&target, "precious\n")?;
+        std::os::unix::fs::symlink(&target, &path)?;
+        let pidfile = super::PidFile::create(&path)?;
+        assert!(!fs::symlink_metadata(&path)?.file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&target)?, "precious\n");
+        assert_eq!(super::read_pidfile(&path), Some(own_pid));
+        drop(pidfile);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegate_to() -> Result<(), Box<dyn std::error::Error>> {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = env::temp_dir().join(format!("pinentry-rofi-delegate-{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let stub = dir.join("pinentry-stub");
+        fs::write(
+            &stub,
+            "#!/bin/sh\necho 'OK Pleased to meet you'\nwhile read -r cmd; do echo \"OK $cmd\"; done\nexit 3\n",
+        )?;
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755))?;
+        fs::write(dir.join("stdin"), "GETPIN\nBYE\n")?;
+
+        let status = super::delegate_to(
+            stub.to_str().unwrap(),
+            fs::File::open(dir.join("stdin"))?.into(),
+            fs::File::create(dir.join("stdout"))?.into(),
+        )?;
+        let output = fs::read_to_string(dir.join("stdout"))?;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(output, "OK Pleased to meet you\nOK GETPIN\nOK BYE\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_looks_like_secret() {
+        use super::looks_like_secret;
+
+        for secret in [
+            "Zx8Kq2Lm9Pw4Rt7Vb3Nc",
+            "aGVsbG8gV29ybGQhIFNlY3JldDEyMw",
+            "correctHorse7Battery9Staple",
+        ] {
+            assert!(looks_like_secret(secret), "{secret}");
+        }
+        for benign in [
+            "",
+            "C",
+            "tmux-256color",
+            "en_US.UTF-8",
+            "/run/user/1000/gnupg/S.gpg-agent",
+            "DEADBEEF0123456789ABCDEF01234567",
+            "Do you really want to make your passphrase visible on the screen?",
+            "aaaaaaaaaaaaaaaaaaaaAAAAAAAAAA1111",
+        ] {
+            assert!(!looks_like_secret(benign), "{benign}");
+        }
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        use super::redact_secrets;
+
+        assert_eq!(redact_secrets("OPTION ttytype=xterm"), "OPTION ttytype=xterm");
+        assert_eq!(
+            redact_secrets("OPTION owner=Zx8Kq2Lm9Pw4Rt7Vb3Nc"),
+            "OPTION owner=[redacted]"
+        );
+        assert_eq!(
+            redact_secrets("SETDESC Zx8Kq2Lm9Pw4Rt7Vb3Nc and correctHorse7Battery9Staple"),
+            "SETDESC [redacted] and [redacted]"
+        );
+    }
+
+    #[test]
+    fn test_secrets_not_logged() {
+        let capture = LogCapture::start();
+        let mut rofi = MockRofi::default();
+        let output = crate::testing::run_session(&[], "OPTION owner=Zx8Kq2Lm9Pw4Rt7Vb3Nc\nBYE\n", &mut rofi).unwrap();
+        assert!(output.starts_with("OK Please go ahead\n"), "{output}");
+
+        let lines = capture.lines();
+        assert!(
+            lines.iter().any(|line| line.ends_with("<- OPTION owner=[redacted]")),
+            "{lines:?}"
+        );
+        assert!(
+            lines.iter().all(|line| !line.contains("Zx8Kq2Lm9Pw4Rt7Vb3Nc")),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn test_ttyinfo_reports_rofi_display() {
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&[&OPTION_VARS[..], &["DISPLAY"]].concat());
+        env::set_var("DISPLAY", ":42");
+        let args = test_args(&["--display", ":7"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [
+            ("OPTION", "ttyname=/dev/pts/1"),
+            ("OPTION", "ttytype=tmux-256color"),
+            ("GETINFO", "ttyinfo"),
+        ] {
+            super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nOK\nD /dev/pts/1 tmux-256color :7\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_prompt_as_password_placeholder() {
+        assert_eq!(super::rasi_string(r#"Say "hi" \o/"#), r#""Say \"hi\" \\o/""#);
+
+        for (extra, prompt, theme) in [
+            (
+                &["--prompt-as-password-placeholder"][..],
+                Some(""),
+                Some(r#"entry { placeholder: "PIN"; }"#),
+            ),
+            (
+                &["--entry-placeholder", r#"6 "digits""#][..],
+                Some("PIN"),
+                Some(r#"entry { placeholder: "6 \"digits\""; }"#),
+            ),
+            (&[][..], Some("PIN"), None),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [("SETPROMPT", "PIN:"), ("GETPIN", "")] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            let (rofi_args, _) = &rofi.calls[0];
+            assert_eq!(arg_value(rofi_args, "-p"), prompt);
+            assert_eq!(arg_value(rofi_args, "-theme-str"), theme);
+        }
+
+        // The label stays blank when there is no prompt to move into the entry
+        let args = test_args(&["--prompt-as-password-placeholder"]);
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "GETPIN",
+            "",
+            &mut super::State::default(),
+            &args,
+            &mut rofi,
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(arg_value(&rofi.calls[0].0, "-p"), Some(""));
+
+        assert!(super::cmd()
+            .try_get_matches_from([
+                "pinentry-rofi",
+                "--prompt-as-password-placeholder",
+                "--entry-placeholder",
+                "x"
+            ])
+            .is_err());
+    }
+
+    /// A rofi that exits `exits_after` its SIGTERM (never if `None`) or on SIGKILL, on a fake clock
+    struct FakeChild {
+        clock: Rc<Cell<Duration>>,
+        exits_after: Option<Duration>,
+        signals: Vec<(libc::c_int, Duration)>,
+    }
+
+    impl super::Terminable for FakeChild {
+        fn signal(&mut self, signal: libc::c_int) -> std::io::Result<()> {
+            self.signals.push((signal, self.clock.get()));
+            Ok(())
+        }
+
+        fn exited(&mut self) -> std::io::Result<bool> {
+            Ok(self.signals.iter().any(|&(signal, at)| match signal {
+                libc::SIGTERM => self.exits_after.is_some_and(|after| self.clock.get() >= at + after),
+                _ => true,
+            }))
+        }
+    }
+
+    fn run_terminate(signal: super::TimeoutSignal, exits_after: Option<Duration>) -> Vec<(libc::c_int, Duration)> {
+        let clock = Rc::new(Cell::new(Duration::ZERO));
+        let mut child = FakeChild {
+            clock: clock.clone(),
+            exits_after,
+            signals: Vec::new(),
+        };
+        let mut sleep = |step| clock.set(clock.get() + step);
+        assert!(!super::wait_for_exit(&mut child, Duration::from_secs(2), &mut sleep).unwrap());
+        super::terminate(&mut child, signal, Duration::from_millis(500), sleep).unwrap();
+        assert!(super::wait_for_exit(&mut child, Duration::ZERO, &mut |_| {}).unwrap());
+        child.signals
+    }
+
+    #[test]
+    fn test_timeout_termination() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            run_terminate(super::TimeoutSignal::Term, None),
+            [(libc::SIGTERM, ms(2000)), (libc::SIGKILL, ms(2500))]
+        );
+        assert_eq!(
+            run_terminate(super::TimeoutSignal::Term, Some(ms(100))),
+            [(libc::SIGTERM, ms(2000))]
+        );
+        assert_eq!(
+            run_terminate(super::TimeoutSignal::Kill, None),
+            [(libc::SIGKILL, ms(2000))]
+        );
+    }
+
+    #[test]
+    fn test_confirm_timeout() {
+        let secs = Duration::from_secs;
+        for (extra, settimeout, pin_timeout, confirm_timeout) in [
+            (&[][..], "30", Some(secs(30)), Some(secs(30))),
+            (&["--confirm-timeout", "5"][..], "30", Some(secs(30)), Some(secs(5))),
+            (&["--confirm-timeout", "5"][..], "0", None, Some(secs(5))),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [("SETTIMEOUT", settimeout), ("GETPIN", ""), ("CONFIRM", "")] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            assert_eq!(rofi.timeouts, [pin_timeout, confirm_timeout], "{extra:?} {settimeout}");
+        }
+    }
+
+    #[test]
+    fn test_settimeout() {
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi {
+            time_out: true,
+            ..Default::default()
+        };
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [
+            ("SETTIMEOUT", "30"),
+            ("GETPIN", ""),
+            ("CONFIRM", ""),
+            ("SETTIMEOUT", "0"),
+        ] {
+            super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(rofi.timeouts, [Some(Duration::from_secs(30)); 2]);
+        assert_eq!(state.timeout, None);
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nERR 83886142 Timeout\nERR 83886142 Timeout\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_log_level() {
+        use log::Log;
+
+        let logger = super::Logger::new(Vec::new(), super::LogLevel::Info);
+        for (level, line) in [
+            (log::Level::Debug, "<- GETPIN"),
+            (log::Level::Info, "Display `:1` is unavailable"),
+            (log::Level::Error, "pre-cmd `false` failed"),
+        ] {
+            logger.log(&log::Record::builder().level(level).args(format_args!("{line}")).build());
+        }
+        assert_eq!(
+            String::from_utf8(logger.sink.into_inner().unwrap()).unwrap(),
+            "pinentry-rofi: INFO: Display `:1` is unavailable\npinentry-rofi: ERROR: pre-cmd `false` failed\n"
+        );
+    }
+
+    /// Log lines at any level logged while it is alive, by the thread that started it or, for
+    /// `any_thread`, by all of them
+    struct LogCapture {
+        lines: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    type Captures = std::sync::Mutex<Vec<(Option<std::thread::ThreadId>, std::sync::Arc<std::sync::Mutex<Vec<u8>>>)>>;
+
+    static CAPTURES: Captures = std::sync::Mutex::new(Vec::new());
+
+    /// Hands records to `super::Logger` only while a capture is running, so tests without one
+    /// don't log at all
+    struct CaptureLogger(super::Logger<CaptureSink>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            !CAPTURES.lock().unwrap().is_empty() && self.0.enabled(metadata)
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.log(record);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    struct CaptureSink;
+
+    impl std::io::Write for CaptureSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let current = std::thread::current().id();
+            for (thread, lines) in CAPTURES.lock().unwrap().iter() {
+                if thread.is_none_or(|thread| thread == current) {
+                    lines.lock().unwrap().extend_from_slice(buf);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogCapture {
+        fn start() -> Self {
+            Self::register(Some(std::thread::current().id()))
+        }
+
+        /// For code that logs from threads of its own
+        fn any_thread() -> Self {
+            Self::register(None)
+        }
+
+        fn register(thread: Option<std::thread::ThreadId>) -> Self {
+            static INSTALL: std::sync::Once = std::sync::Once::new();
+            INSTALL.call_once(|| {
+                let logger = CaptureLogger(super::Logger::new(CaptureSink, super::LogLevel::Debug));
+                // Whoever got there first keeps logging their way; captures then stay empty
+                if log::set_boxed_logger(Box::new(logger)).is_ok() {
+                    log::set_max_level(log::LevelFilter::Debug);
+                }
+            });
+            let lines = std::sync::Arc::default();
+            CAPTURES.lock().unwrap().push((thread, std::sync::Arc::clone(&lines)));
+            LogCapture { lines }
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.lines.lock().unwrap())
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    impl Drop for LogCapture {
+        fn drop(&mut self) {
+            CAPTURES
+                .lock()
+                .unwrap()
+                .retain(|(_, lines)| !std::sync::Arc::ptr_eq(lines, &self.lines));
+        }
+    }
+
+    #[test]
+    fn test_dialog_timing_trace() {
+        use super::Rofi;
+
+        let capture = LogCapture::start();
+        let rofi = MockRofi::with_outputs(vec![rofi_output(0, "timing-secret\n", "")]);
+        let (output, _) = run_getpin_session(&[], &[], rofi);
+        assert_eq!(output, "D timing-secret\nOK\n");
+        let mut process = super::RofiProcess::new(&test_args(&["--rofi-bin", "true"]));
+        process.run(&[], None, None, &[]).unwrap();
+
+        let lines = capture.lines();
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("pinentry-rofi: DEBUG: Dialog timing: pre-cmd ")
+                && line.contains(", rofi ")
+                && line.contains(", post-cmd ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("pinentry-rofi: DEBUG: rofi started in ")));
+        assert!(!lines.iter().any(|line| line.contains("timing-secret")));
+    }
+
+    #[test]
+    fn test_redirect_stderr_to_log() {
+        use super::Rofi;
+
+        let capture = LogCapture::start();
+        let script = |noise: &str| vec!["-c".to_owned(), format!("echo {noise} >&2; echo pin")];
+        for (extra, noise) in [
+            (&[][..], "stderr-kept-quiet"),
+            (
+                &["--redirect-stderr-to-log", "--log-file", "rofi.log"][..],
+                "stderr-logged",
+            ),
+        ] {
+            let mut process = super::RofiProcess::new(&test_args(&[&["--rofi-bin", "sh"], extra].concat()));
+            let output = process.run(&script(noise), None, None, &[]).unwrap();
+            // Never echoed into the answer
+            match super::RofiOutcome::from(output) {
+                super::RofiOutcome::Pin(pin) => assert_eq!(*pin, "pin"),
+                outcome => panic!("expected the PIN: {outcome:?}"),
+            }
+        }
+        let lines = capture.lines();
+        assert!(lines.contains(&"pinentry-rofi: WARN: rofi: stderr-logged".to_owned()));
+        assert!(!lines.iter().any(|line| line.contains("stderr-kept-quiet")));
+
+        // Still the error detail on failure
+        let mut process = super::RofiProcess::new(&test_args(&[
+            "--rofi-bin",
+            "sh",
+            "--redirect-stderr-to-log",
+            "--log-file",
+            "rofi.log",
+        ]));
+        let output = process
+            .run(
+                &["-c".to_owned(), "echo broken >&2; exit 2".to_owned()],
+                None,
+                None,
+                &[],
+            )
+            .unwrap();
+        match super::RofiOutcome::from(output) {
+            super::RofiOutcome::Error(err) => assert_eq!(err, "broken"),
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_log_file() {
+        use log::Log;
+
+        let path = env::temp_dir().join(format!("pinentry-rofi-log-{}", process::id()));
+        std::fs::write(&path, "earlier\n").unwrap();
+        let args = test_args(&["--log-file", path.to_str().unwrap(), "--redirect-stderr-to-log"]);
+        // rofi's stderr is logged at warn, below the default error level
+        assert_eq!(args.log_filter(), log::LevelFilter::Warn);
+        let logger = super::Logger::new(super::log_sink(&args).unwrap(), args.log_filter());
+        for (level, line) in [(log::Level::Warn, "rofi: noise"), (log::Level::Info, "not logged")] {
+            logger.log(&log::Record::builder().level(level).args(format_args!("{line}")).build());
+        }
+        drop(logger);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "earlier\npinentry-rofi: WARN: rofi: noise\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            test_args(&["--log-level", "debug"]).log_filter(),
+            log::LevelFilter::Debug
+        );
+        // Nowhere to redirect to without a log file
+        assert!(super::cmd()
+            .try_get_matches_from(["pinentry-rofi", "--redirect-stderr-to-log"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_bounded() {
+        let pin = super::read_bounded(&mut "secret\n".as_bytes(), 8).unwrap();
+        assert_eq!(*pin, b"secret\n");
+        let pin = super::read_bounded(&mut "12345678".as_bytes(), 8).unwrap();
+        assert_eq!(*pin, b"12345678");
+        assert!(super::read_bounded(&mut "".as_bytes(), 0).unwrap().is_empty());
+
+        let err = super::read_bounded(&mut "123456789".as_bytes(), 8).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // Stops at the limit instead of draining an endless stream
+        let err = super::read_bounded(&mut std::io::repeat(b'x'), 4096).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_bounded_read_with_chatty_stderr() {
+        use super::Rofi;
+
+        // More stderr than a pipe holds, written before the PIN
+        let script = ["-c".to_owned(), "head -c 200000 /dev/zero >&2; echo pin".to_owned()];
+        for extra in [&[][..], &["--max-pin-len", "8"][..]] {
+            let mut process = super::RofiProcess::new(&test_args(&[&["--rofi-bin", "sh"], extra].concat()));
+            let output = process.run(&script, None, None, &[]).unwrap();
+            assert_eq!(output.stdout, b"pin\n", "{extra:?}");
+            assert_eq!(output.stderr.len(), 200000, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn test_max_pin_len() {
+        use super::Rofi;
+
+        for (extra, stdout, fits) in [
+            (&[][..], "12345678\n", true),
+            (&[][..], "12345678\r\n", true),
+            (&[][..], "123456789\n", false),
+            (&[][..], "123456789", false),
+            (&["--end-marker", "::"][..], "12345678::\n", true),
+            (&["--end-marker", "::"][..], "123456789::\n", false),
+        ] {
+            let args = test_args(&[&["--rofi-bin", "sh", "--max-pin-len", "8"], extra].concat());
+            let mut process = super::RofiProcess::new(&args);
+            let script = ["-c".to_owned(), format!("printf '{stdout}'")];
+            match process.run(&script, None, None, &[]) {
+                Ok(output) => {
+                    assert!(fits, "{stdout:?}");
+                    assert_eq!(output.stdout, stdout.as_bytes());
+                }
+                Err(err) => {
+                    assert!(!fits, "{stdout:?}: {err}");
+                    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+                    assert_eq!(err.to_string(), "the passphrase is longer than 8 bytes");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_foreground() {
+        let (_, rofi) = run_getpin_session(&[], &[], MockRofi::default());
+        assert!(!rofi.calls[0].0.contains(&"-steal-focus".to_owned()));
+
+        let (_, rofi) = run_getpin_session(&["--foreground"], &[], MockRofi::default());
+        assert!(rofi.calls[0].0.contains(&"-steal-focus".to_owned()));
+
+        let (_, rofi) = run_confirm_session_with_args("", MockRofi::default(), &["--foreground"], &[]);
+        assert!(rofi.calls[0].0.contains(&"-steal-focus".to_owned()));
+    }
+
+    #[test]
+    fn test_warn_to_client() {
+        let mut writer = super::Writer::new(Vec::new());
+        writer.warn_to_client("theme parse failed, using default").unwrap();
+        writer.warn_to_client("two\nlines\r").unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "# Warning: theme parse failed, using default\n# Warning: two lines \n"
+        );
+
+        let args = test_args(&["--ime-friendly"]);
+        let mut writer = super::Writer::new(Vec::new());
+        for option in ["grab", "no-grab"] {
+            super::handle_command(
+                "OPTION",
+                option,
+                &mut super::State::default(),
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "# Warning: OPTION grab ignored, --ime-friendly keeps the keyboard ungrabbed\nOK\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_ime_friendly() {
+        let (_, rofi) = run_getpin_session(&[], &["grab"], MockRofi::default());
+        assert!(!rofi.calls[0].0.contains(&"-normal-window".to_owned()));
+
+        for options in [&[][..], &["grab"][..]] {
+            let (_, rofi) = run_getpin_session(&["--ime-friendly"], options, MockRofi::default());
+            assert!(rofi.calls[0].0.contains(&"-normal-window".to_owned()), "{options:?}");
+        }
+
+        let (_, rofi) = run_confirm_session_with_args("", MockRofi::default(), &["--ime-friendly"], &["grab"]);
+        assert!(rofi.calls[0].0.contains(&"-normal-window".to_owned()));
+    }
+
+    #[test]
+    fn test_theme_str() {
+        let theme_strs = |rofi_args: &[String]| {
+            rofi_args
+                .windows(2)
+                .filter(|pair| pair[0] == "-theme-str")
+                .map(|pair| pair[1].to_owned())
+                .collect::<Vec<_>>()
+        };
+        let extra = [
+            "--theme-str",
+            "window { width: 30%; }",
+            "--theme-str",
+            "listview { lines: 2; }",
+        ];
+
+        let (_, rofi) = run_getpin_session(&extra, &[], MockRofi::default());
+        assert_eq!(
+            theme_strs(&rofi.calls[0].0),
+            ["window { width: 30%; }", "listview { lines: 2; }"]
+        );
+
+        let (_, rofi) = run_confirm_session_with_args("", MockRofi::default(), &extra, &[]);
+        assert_eq!(
+            theme_strs(&rofi.calls[0].0),
+            ["window { width: 30%; }", "listview { lines: 2; }"]
+        );
+
+        // Alongside the one generated for the placeholder
+        let args = test_args(&[&extra[..], &["--prompt-as-password-placeholder"]].concat());
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [("SETPROMPT", "PIN:"), ("GETPIN", "")] {
+            super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(
+            theme_strs(&rofi.calls[0].0),
+            [
+                r#"entry { placeholder: "PIN"; }"#,
+                "window { width: 30%; }",
+                "listview { lines: 2; }"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_passphrase_context() {
+        use super::{passphrase_context, PassphraseContext::*};
+
+        for (desc, context) in [
+            (
+                "Please enter the passphrase to unlock the OpenPGP secret key:\n\"Alice <alice@example.org>\"\n\
+                 255-bit EDDSA key, ID 0x1234, created 2024-01-01.",
+                Some(Key),
+            ),
+            (
+                "Please enter the passphrase to import the OpenPGP secret key:",
+                Some(Key),
+            ),
+            ("Please enter the passphrase to protect your new key", Some(Key)),
+            (
+                "Please enter the passphrase to unlock the secret key for the X.509 certificate:",
+                Some(Key),
+            ),
+            ("Please enter the passphrase for decryption.", Some(Symmetric)),
+            ("Enter passphrase for symmetric encryption", Some(Symmetric)),
+            ("Please enter the passphrase to ENCRYPT the data", Some(Symmetric)),
+            ("Please enter your PIN", None),
+            ("", None),
+        ] {
+            assert_eq!(passphrase_context(desc), context, "{desc}");
+        }
+
+        let map = "[symmetric]\nprompt = Symmetric\n[key]\nprompt = Key\n[ABCD]\nprompt = Work key\n";
+        for (desc, keyinfo, prompt) in [
+            ("Please enter the passphrase for decryption.", None, "Symmetric"),
+            (
+                "Please enter the passphrase to unlock the OpenPGP secret key:",
+                None,
+                "Key",
+            ),
+            // A key named by SETKEYINFO is a key whatever the wording, and its own section wins
+            ("Please enter the passphrase", Some("n/0000"), "Key"),
+            ("Please enter the passphrase", Some("s/0000"), "Key"),
+            // gpg-agent names symmetric passphrases by cache id with the `u/` mode
+            ("Please enter the passphrase", Some("u/0123abcd"), "Symmetric"),
+            // An unknown mode leaves it to the wording
+            (
+                "Please enter the passphrase for decryption.",
+                Some("x/0000"),
+                "Symmetric",
+            ),
+            (
+                "Please enter the passphrase for decryption.",
+                Some("n/ABCD"),
+                "Work key",
+            ),
+            ("Please enter your PIN", None, "PIN"),
+        ] {
+            let args = test_args(&[]);
+            let mut state = super::State {
+                key_profiles: super::parse_key_profiles(map).unwrap(),
+                ..Default::default()
+            };
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            let desc = urlencoding::encode(desc);
+            let mut commands = vec![("SETPROMPT", "PIN:"), ("SETDESC", desc.as_ref())];
+            commands.extend(keyinfo.map(|keyinfo| ("SETKEYINFO", keyinfo)));
+            commands.push(("GETPIN", ""));
+            for (action, arg) in commands {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            assert_eq!(arg_value(&rofi.calls[0].0, "-p"), Some(prompt), "{desc} {keyinfo:?}");
+        }
+    }
+
+    #[test]
+    fn test_key_profiles() {
+        let profiles = super::parse_key_profiles(
+            "# work and personal keys\n\
+             [abcdef0123]\n\
+             prompt = Work key\n\
+             theme-str = window { border-color: #f00; }\n\
+             \n\
+             [9876543210]\n\
+             prompt=Personal\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profiles["ABCDEF0123"],
+            super::KeyProfile {
+                prompt: Some("Work key".to_owned()),
+                theme_str: Some("window { border-color: #f00; }".to_owned()),
+            }
+        );
+        assert_eq!(profiles["9876543210"].theme_str, None);
+        assert!(super::parse_key_profiles("prompt = orphan").is_err());
+        assert!(super::parse_key_profiles("[AB]\ncolor = red").is_err());
+        assert!(super::parse_key_profiles("[AB]\nprompt").is_err());
+
+        for (keyinfo, prompt, theme) in [
+            ("n/ABCDEF0123", "Work key", Some("window { border-color: #f00; }")),
+            ("s/9876543210", "Personal", None),
+            // Unmapped keys and cleared key info fall back to the defaults
+            ("n/0000000000", "PIN", None),
+            ("--clear", "PIN", None),
+        ] {
+            let args = test_args(&[]);
+            let mut state = super::State {
+                key_profiles: super::parse_key_profiles(
+                    "[ABCDEF0123]\nprompt = Work key\ntheme-str = window { border-color: #f00; }\n\
+                     [9876543210]\nprompt = Personal\n",
+                )
+                .unwrap(),
+                ..Default::default()
+            };
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [("SETPROMPT", "PIN:"), ("SETKEYINFO", keyinfo), ("GETPIN", "")] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            let (rofi_args, _) = &rofi.calls[0];
+            assert_eq!(arg_value(rofi_args, "-p"), Some(prompt), "{keyinfo}");
+            assert_eq!(arg_value(rofi_args, "-theme-str"), theme, "{keyinfo}");
+        }
+    }
+
+    #[test]
+    fn test_run_session_setup() {
+        // run_session sets dialogs up from the command line like pinentry does
+        let map = env::temp_dir().join(format!("pinentry-rofi-keymap-{}", process::id()));
+        std::fs::write(&map, "[ABCD]\nprompt = Work key\n").unwrap();
+        let mut rofi = MockRofi::default();
+        let output = crate::testing::run_session(
+            &[
+                "--display",
+                ":0",
+                "--prompt",
+                "Passphrase",
+                "--monitor",
+                "1",
+                "--prompt-history-salt",
+                "--keygrip-map",
+                map.to_str().unwrap(),
+            ],
+            "GETPIN\nSETKEYINFO n/abcd\nGETPIN\n",
+            &mut rofi,
+        );
+        std::fs::remove_file(&map).unwrap();
+        assert_eq!(output.unwrap(), "OK Please go ahead\nOK\nOK\nOK\n");
+        let calls = rofi.calls();
+        assert_eq!(arg_value(&calls[0].0, "-p"), Some("Passphrase"));
+        assert_eq!(arg_value(&calls[1].0, "-p"), Some("Work key"));
+        assert_eq!(arg_value(&calls[0].0, "-m"), Some("1"));
+        assert_ne!(arg_value(&calls[0].0, "-input"), Some("/dev/null"));
+
+        let mut rofi = MockRofi::default();
+        let err = crate::testing::run_session(&["--keygrip-map", "/nonexistent/keys"], "", &mut rofi).unwrap_err();
+        assert!(err.to_string().starts_with("/nonexistent/keys: "), "{err}");
+    }
+
+    #[test]
+    fn test_pinentry_mode_hint() {
+        let args = test_args(&[]);
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for mode in ["ask", "loopback"] {
+            let mut state = super::State::default();
+            let option = format!("pinentry-mode={mode}");
+            for (action, arg) in [("OPTION", option.as_str()), ("GETPIN", ""), ("CONFIRM", "")] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            assert_eq!(state.options["pinentry-mode"], mode);
+        }
+        // Only the "ask" session showed rofi
+        assert_eq!(rofi.calls.len(), 2);
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nOK\nERR 83886179 Operation cancelled <rofi>\n\
+             OK\n\
+             ERR 83886140 Not supported <loopback pinentry mode>\n\
+             ERR 83886140 Not supported <loopback pinentry mode>\n"
+        );
+    }
+
+    #[test]
+    fn test_no_display_short_circuit() {
+        let args = test_args(&["--display", ":7"]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi {
+            no_display: true,
+            ..Default::default()
+        };
+        let mut writer = super::Writer::new(Vec::new());
+        for action in ["GETPIN", "CONFIRM"] {
+            super::handle_command(action, "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert!(rofi.calls.is_empty());
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "ERR 83886165 No pinentry <display `:7` is unavailable>\n".repeat(2)
+        );
+
+        // Trusting the display despite the check
+        let args = test_args(&["--display", ":7", "--no-display-check"]);
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        assert_eq!(rofi.calls.len(), 1);
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\n");
+    }
+
+    #[test]
+    fn test_sanitize_prompt() {
+        assert_eq!(super::sanitize_prompt("Work key"), "Work key");
+        assert_eq!(
+            super::sanitize_prompt("Work\nkey\0 -theme\x1b[31m"),
+            "Work key -theme [31m"
+        );
+        assert_eq!(
+            super::sanitize_prompt("  tabs\t\t and\r\nlines \u{85}"),
+            "tabs and lines"
+        );
+        assert_eq!(super::sanitize_prompt("\n\0"), "");
+
+        assert!(test_args(&[]).sanitize_prompt);
+        assert!(!test_args(&["--sanitize-prompt", "false"]).sanitize_prompt);
+    }
+
+    #[test]
+    fn test_version_and_flavor() {
+        assert_eq!(super::version(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(super::flavor(), "keyring");
+        for (info, value) in [("version", super::version()), ("flavor", super::flavor())] {
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "GETINFO",
+                info,
+                &mut super::State::default(),
+                &test_args(&[]),
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                format!("D {value}\nOK\n")
+            );
+        }
+    }
+
+    #[test]
+    fn test_getinfo_restricted() {
+        for (extra, reply) in [(&[][..], "D 0\nOK\n"), (&["--no-env-options"][..], "D 1\nOK\n")] {
+            let args = test_args(extra);
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "GETINFO",
+                "restricted",
+                &mut super::State::default(),
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), reply);
+        }
+    }
+
+    #[test]
+    fn test_data_lines() {
+        let lines = |data: &str| {
+            super::data_lines(data)
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        let joined = |lines: &[String]| {
+            let data: String = lines.iter().map(|line| line.strip_prefix("D ").unwrap()).collect();
+            urlencoding::decode(&data).unwrap().into_owned()
+        };
+
+        assert_eq!(lines("secret"), ["D secret"]);
+        assert_eq!(lines("100%\r\nsure"), ["D 100%25%0D%0Asure"]);
+
+        // 997 bytes of data fit a line
+        let long = "a".repeat(2000);
+        let chunks = lines(&long);
+        assert_eq!(chunks.iter().map(String::len).collect::<Vec<_>>(), [999, 999, 8]);
+        assert_eq!(joined(&chunks), long);
+
+        // An escape that doesn't fit moves to the next line whole
+        for tail in ["%", "\n"] {
+            let data = "a".repeat(995) + tail + "b";
+            let chunks = lines(&data);
+            assert_eq!(chunks[0], format!("D {}", "a".repeat(995)));
+            assert!(chunks[1].starts_with("D %"), "{tail:?}");
+            assert_eq!(joined(&chunks), data);
+        }
+        // So does a multibyte character
+        let data = "a".repeat(996) + "ф";
+        let chunks = lines(&data);
+        assert_eq!(chunks, [format!("D {}", "a".repeat(996)), "D ф".to_owned()]);
+
+        let (output, _) = run_getpin_session(
+            &[],
+            &[],
+            MockRofi::with_outputs(vec![rofi_output(0, &format!("{long}\n"), "")]),
+        );
+        let reply: Vec<_> = output.lines().map(str::to_owned).collect();
+        assert_eq!(reply.len(), 4);
+        assert_eq!(reply[3], "OK");
+        assert_eq!(joined(&reply[..3]), long);
+    }
+
+    #[test]
+    fn test_tty_size() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::io::AsRawFd;
+
+        let pty = super::open_tty("/dev/ptmx")?;
+        let size = libc::winsize {
+            ws_row: 40,
+            ws_col: 120,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCSWINSZ only reads the winsize.
+        assert_eq!(unsafe { libc::ioctl(pty.as_raw_fd(), libc::TIOCSWINSZ, &size) }, 0);
+        assert_eq!(super::tty_size(&pty)?, (40, 120));
+
+        let not_a_tty = super::open_tty("/dev/null")?;
+        assert_eq!(
+            super::tty_size(&not_a_tty).unwrap_err().raw_os_error(),
+            Some(libc::ENOTTY)
+        );
+        assert_eq!(super::open_tty("").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // The tty from OPTION ttyname; not a terminal here
+        let args = test_args(&["--no-env-options"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [("OPTION", "ttyname=/dev/null"), ("GETINFO", "ttysize")] {
+            super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer)?;
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner())?,
+            "OK\nERR 83886140 Not supported <no size for tty `/dev/null`: Inappropriate ioctl for device (os error 25)>\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_getinfo_replies_cached() {
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&["GPG_TTY"]);
+        env::set_var("GPG_TTY", "/dev/pts/3");
+        let args = test_args(&["--no-env-options"]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::default();
+        let mut getinfo = |state: &mut super::State, what: &str| {
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command("GETINFO", what, state, &args, &mut rofi, &mut writer).unwrap();
+            String::from_utf8(writer.into_inner()).unwrap()
+        };
+
+        let replies: Vec<_> = ["ttyinfo", "version", "flavor"]
+            .into_iter()
+            .map(|what| getinfo(&mut state, what))
+            .collect();
+        assert!(replies[0].starts_with("D /dev/pts/3 "));
+        // The environment changing under us doesn't change the answers
+        env::set_var("GPG_TTY", "/dev/pts/4");
+        for (what, reply) in ["ttyinfo", "version", "flavor"].into_iter().zip(&replies) {
+            assert_eq!(&getinfo(&mut state, what), reply, "{what}");
+        }
+
+        // An OPTION from the client is taken into account
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "OPTION",
+            "ttytype=vt100",
+            &mut state,
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(getinfo(&mut state, "ttyinfo"), "D /dev/pts/4 vt100 :0\nOK\n");
+    }
+
+    #[cfg(feature = "insecure-testing")]
+    #[test]
+    fn test_insecure_pin_from_env() {
+        let _lock = lock_env();
+        let var = "PINENTRY_ROFI_TEST_PIN";
+        env::set_var(var, "from env%");
+        let (output, rofi) = run_getpin_session(&["--insecure-pin-from-env", var], &[], MockRofi::default());
+        assert_eq!(
+            output,
+            "# Warning: INSECURE: passphrase taken from $PINENTRY_ROFI_TEST_PIN without asking, for testing only\n\
+             D from env%25\nOK\n"
+        );
+        assert!(rofi.calls.is_empty());
+
+        env::remove_var(var);
+        let (output, rofi) = run_getpin_session(&["--insecure-pin-from-env", var], &[], MockRofi::default());
+        assert!(output
+            .ends_with("ERR 83886179 Operation cancelled <$PINENTRY_ROFI_TEST_PIN: environment variable not found>\n"));
+        assert!(rofi.calls.is_empty());
+    }
+
+    #[test]
+    fn test_getinfo_cmd_progress() {
+        for extra in [&[][..], &["--show-attempts", "--no-env-options"][..]] {
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "GETINFO",
+                "cmd_progress",
+                &mut super::State::default(),
+                &test_args(extra),
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                "D 0\nOK\n",
+                "{extra:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_getinfo_backend() {
+        for (extra, reply) in [
+            (&[][..], "D rofi;timeout\nOK\n"),
+            (&["--no-env-options"][..], "D rofi;timeout;restricted\nOK\n"),
+            (
+                &["--auto-confirm", "never", "--confirm-timeout", "5", "--show-attempts"][..],
+                "D rofi;timeout;confirm-timeout;show-attempts\nOK\n",
+            ),
+            (
+                &["--auto-confirm", "yes", "--kill-existing", "--max-pin-len", "64"][..],
+                "D rofi;timeout;auto-confirm;kill-existing;max-pin-len\nOK\n",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "GETINFO",
+                "backend",
+                &mut super::State::default(),
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), reply, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn test_newline_repr() {
+        for (extra, mesg) in [
+            (&[][..], "Bad\r***************************\rUnlock\rkey"),
+            (
+                &["--newline-repr", "cr"][..],
+                "Bad\r***************************\rUnlock\rkey",
+            ),
+            (
+                &["--newline-repr", "lf"][..],
+                "Bad\n***************************\nUnlock\nkey",
+            ),
+            (
+                &["--newline-repr", "glyph"][..],
+                "Bad ⏎ *************************** ⏎ Unlock ⏎ key",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::with_outputs(vec![rofi_output(0, "OK\n", ""), rofi_output(0, "pin\n", "")]);
+            let mut writer = super::Writer::new(Vec::new());
+            // CONFIRM first, an answered GETPIN clears the error
+            for (action, arg) in [
+                ("SETDESC", "Unlock%0Akey"),
+                ("SETERROR", "Bad"),
+                ("CONFIRM", ""),
+                ("GETPIN", ""),
+            ] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            assert_eq!(arg_value(&rofi.calls[0].0, "-mesg"), Some(mesg), "{extra:?}");
+            assert_eq!(arg_value(&rofi.calls[1].0, "-mesg"), Some(mesg), "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn test_localized_messages() {
+        use super::{localized, Message};
+
+        for (locale, cancelled) in [
+            (None, "Operation cancelled"),
+            (Some("C"), "Operation cancelled"),
+            (Some("POSIX"), "Operation cancelled"),
+            (Some("ja_JP.UTF-8"), "Operation cancelled"),
+            (Some("de_DE.UTF-8"), "Vorgang abgebrochen"),
+            (Some("de"), "Vorgang abgebrochen"),
+            (Some("fr_CA"), "Opération annulée"),
+            (Some("es_ES@euro"), "Operación cancelada"),
+            (Some("ru_RU.KOI8-R"), "Операция отменена"),
+        ] {
+            assert_eq!(localized(Message::Cancelled, locale), cancelled, "{locale:?}");
+        }
+        assert_eq!(localized(Message::Timeout, Some("de_AT")), "Zeitüberschreitung");
+        assert_eq!(localized(Message::RofiNotFound, Some("en_GB")), "rofi not found");
+
+        let (output, _) = run_getpin_session(
+            &["--no-env-options"],
+            &["lc-messages=de_DE.UTF-8"],
+            MockRofi::with_outputs(vec![rofi_output(1, "", "")]),
+        );
+        assert_eq!(output, "OK\nERR 83886179 Vorgang abgebrochen <rofi>\n");
+
+        let args = test_args(&["--no-env-options"]);
+        let mut rofi = MockRofi {
+            not_found: true,
+            ..Default::default()
+        };
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "OPTION",
+            "lc-messages=fr_FR.UTF-8",
+            &mut state,
+            &args,
+            &mut rofi,
+            &mut writer,
+        )
+        .unwrap();
+        super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        super::handle_command("CONFIRM", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nERR 83886165 No pinentry <rofi introuvable>\nERR 83886165 No pinentry <rofi introuvable>\n"
+        );
+    }
+
+    #[test]
+    fn test_monitor_arg() {
+        use super::{monitor_arg, parse_listmonitors};
+
+        let outputs = parse_listmonitors(
+            "Monitors: 2\n 0: +*DP-1 2560/597x1440/336+0+0  DP-1\n 1: +HDMI-A-1 1920/527x1080/296+2560+0  HDMI-A-1\n",
+        );
+        assert_eq!(outputs, ["DP-1", "HDMI-A-1"]);
+        assert!(parse_listmonitors("Monitors: 0\n").is_empty());
+
+        let listed = || Some(outputs.clone());
+        assert_eq!(monitor_arg("DP-1", listed).unwrap(), "0");
+        assert_eq!(monitor_arg("HDMI-A-1", listed).unwrap(), "1");
+        assert_eq!(
+            monitor_arg("eDP-1", listed).unwrap_err(),
+            "--monitor `eDP-1` is not a connected output (DP-1, HDMI-A-1)"
+        );
+        assert_eq!(monitor_arg("eDP-1", || None).unwrap(), "eDP-1");
+        // Numbers don't need xrandr at all
+        assert_eq!(monitor_arg("-4", || unreachable!()).unwrap(), "-4");
+        assert_eq!(monitor_arg("3", || unreachable!()).unwrap(), "3");
+    }
+
+    #[test]
+    fn test_masking_warning() {
+        use super::masking_warning;
+
+        for version in [
+            "Version: 1.7.5",
+            "Version: 1.7.5+wayland3\n",
+            "Version: 1.4.0",
+            "Version: 2.0",
+        ] {
+            assert_eq!(masking_warning(Some(version)), None, "{version}");
+        }
+        assert_eq!(
+            masking_warning(Some("Version: 1.3.1")).as_deref(),
+            Some("rofi 1.3 may not mask the passphrase, -password needs 1.4")
+        );
+        for version in [None, Some(""), Some("Version: unknown"), Some("Version: 1")] {
+            assert_eq!(
+                masking_warning(version).as_deref(),
+                Some("could not confirm that rofi masks the passphrase"),
+                "{version:?}"
+            );
+        }
+        let timeout = Duration::from_secs(2);
+        assert_eq!(super::rofi_version("false", timeout), None);
+        assert_eq!(super::rofi_version("/nonexistent/rofi", timeout), None);
+        // A rofi that hangs is given up on
+        let started = std::time::Instant::now();
+        assert_eq!(super::rofi_version("yes", Duration::from_millis(50)), None);
+        assert!(started.elapsed() < timeout);
+
+        let args = test_args(&[]);
+        let mut rofi = MockRofi {
+            masking_warning: masking_warning(None),
+            ..MockRofi::with_outputs(vec![rofi_output(0, "pin\n", "")])
+        };
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "# Warning: could not confirm that rofi masks the passphrase\nD pin\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_history_unchanged() {
+        use super::{changed_history, history_snapshot};
+
+        let dir = env::temp_dir().join(format!("pinentry-rofi-history-{}", process::id()));
+        assert!(history_snapshot(&dir).is_empty());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rofi3.druncache"), "firefox\n").unwrap();
+        std::fs::write(dir.join("other-app"), "").unwrap();
+
+        let before = history_snapshot(&dir);
+        assert_eq!(before.len(), 1);
+        assert!(changed_history(&before, &history_snapshot(&dir)).is_empty());
+
+        std::fs::write(dir.join("other-app"), "ignored").unwrap();
+        std::fs::write(dir.join("rofi-dmenu-history"), "secret\n").unwrap();
+        std::fs::write(dir.join("rofi3.druncache"), "firefox\nsecret\n").unwrap();
+        assert_eq!(
+            changed_history(&before, &history_snapshot(&dir)),
+            [dir.join("rofi-dmenu-history"), dir.join("rofi3.druncache")]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dialog_observer() {
+        use super::DialogOutcome::{self, *};
+        use std::cell::RefCell;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let observer = {
+            let seen = Rc::clone(&seen);
+            Rc::new(move |outcome: DialogOutcome| seen.borrow_mut().push(outcome))
+        };
+        let mut state = super::State {
+            observer: Some(observer),
+            ..Default::default()
+        };
+        let mut rofi = MockRofi::with_outputs(vec![
+            rofi_output(0, "secret\n", ""),
+            rofi_output(1, "", ""),
+            rofi_output(2, "", "broken"),
+            rofi_output(0, "OK\n", ""),
+            rofi_output(0, "Cancel\n", ""),
+        ]);
+        let args = test_args(&[]);
+        let mut writer = super::Writer::new(Vec::new());
+        for action in ["GETPIN", "GETPIN", "GETPIN", "CONFIRM", "CONFIRM"] {
+            super::handle_command(action, "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(
+            *seen.borrow(),
+            [
+                Shown,
+                PinEntered,
+                Shown,
+                Cancelled,
+                Shown,
+                Error,
+                Shown,
+                Confirmed,
+                Shown,
+                NotConfirmed
+            ]
+        );
+
+        // Nothing is shown when answering by itself or when there is no display
+        seen.borrow_mut().clear();
+        let args = test_args(&["--auto-confirm", "yes"]);
+        super::handle_command("CONFIRM", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        let mut rofi = MockRofi {
+            no_display: true,
+            ..Default::default()
+        };
+        super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        assert_eq!(*seen.borrow(), [Confirmed, Error]);
+    }
+
+    #[test]
+    fn test_output_file() {
+        let path = env::temp_dir().join(format!("pinentry-rofi-status-{}", process::id()));
+        let path_arg = path.to_str().unwrap();
+        let status = || std::fs::read_to_string(&path).unwrap();
+
+        for (stdout, code, stderr, expected) in [
+            ("secret\n", 0, "", "ok\n"),
+            ("", 0, "", "ok\n"),
+            ("", 1, "", "cancel\n"),
+            ("", 10, "", "cancel\n"),
+            ("", 2, "broken", "error\n"),
+        ] {
+            let rofi = MockRofi::with_outputs(vec![rofi_output(code, stdout, stderr)]);
+            run_getpin_session(&["--output-file", path_arg], &[], rofi);
+            assert_eq!(status(), expected, "{stdout:?} {code}");
+            assert!(!status().contains("secret"));
+        }
+        run_getpin_session(
+            &["--output-file", path_arg],
+            &[],
+            MockRofi {
+                time_out: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(status(), "error\n");
+
+        for (stdout, code, stderr, expected) in [
+            ("OK\n", 0, "", "ok\n"),
+            ("Cancel\n", 0, "", "cancel\n"),
+            ("", 1, "", "cancel\n"),
+            ("", 2, "broken", "error\n"),
+        ] {
+            let rofi = MockRofi::with_outputs(vec![rofi_output(code, stdout, stderr)]);
+            run_confirm_session_with_args("", rofi, &["--output-file", path_arg], &[]);
+            assert_eq!(status(), expected, "{stdout:?} {code}");
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // Not written without the option
+        run_getpin_session(&[], &[], MockRofi::default());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_numeric_len() {
+        let notice = "Enter exactly 6 digits";
+        for (extra, outputs, reply, notices) in [
+            // Invalid codes are asked again
+            (
+                &["--numeric-len", "6"][..],
+                &["12345\n", "1234567\n", "12a456\n", "\n", "123456\n"][..],
+                "D 123456\nOK\n",
+                4,
+            ),
+            (&["--numeric-len", "6"][..], &["654321\n"][..], "D 654321\nOK\n", 0),
+            // Esc still cancels
+            (
+                &["--numeric-len", "6"][..],
+                &["12\n", ""][..],
+                "ERR 83886179 Operation cancelled <rofi>\n",
+                1,
+            ),
+            (&[][..], &["12a\n"][..], "D 12a\nOK\n", 0),
+        ] {
+            let rofi = MockRofi::with_outputs(
+                outputs
+                    .iter()
+                    .map(|stdout| rofi_output(if stdout.is_empty() { 1 } else { 0 }, stdout, ""))
+                    .collect(),
+            );
+            let (output, rofi) = run_getpin_session(extra, &[], rofi);
+            assert_eq!(output, reply, "{outputs:?}");
+            assert_eq!(rofi.calls.len(), outputs.len());
+            assert_eq!(arg_value(&rofi.calls[0].0, "-mesg"), None);
+            let shown = rofi.calls[1..]
+                .iter()
+                .filter(|(args, _)| arg_value(args, "-mesg") == Some(notice))
+                .count();
+            assert_eq!(shown, notices, "{outputs:?}");
+        }
+    }
+
+    #[test]
+    fn test_password_min_length() {
+        let notice = "The passphrase must be at least 8 characters long";
+        for (extra, setrepeat, first, reply, runs) in [
+            // New passphrase: too short or empty is asked again, at length is accepted
+            (
+                &["--password-min-length", "8"][..],
+                true,
+                "1234567\n",
+                "D 12345678\nOK\n",
+                2,
+            ),
+            (&["--password-min-length", "8"][..], true, "\n", "D 12345678\nOK\n", 2),
+            // Unlocking takes anything
+            (
+                &["--password-min-length", "8"][..],
+                false,
+                "1234567\n",
+                "D 1234567\nOK\n",
+                1,
+            ),
+            (&["--password-min-length", "8"][..], false, "\n", "OK\n", 1),
+            (&[][..], true, "1234567\n", "D 1234567\nOK\n", 1),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::with_outputs(vec![rofi_output(0, first, ""), rofi_output(0, "12345678\n", "")]);
+            let mut writer = super::Writer::new(Vec::new());
+            if setrepeat {
+                super::handle_command("SETREPEAT", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+                assert_eq!(
+                    String::from_utf8(writer.into_inner()).unwrap(),
+                    "ERR 83886140 Not supported <no repeat entry>\n"
+                );
+                writer = super::Writer::new(Vec::new());
+            }
+            super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                reply,
+                "{extra:?} {setrepeat} {first:?}"
+            );
+            assert_eq!(rofi.calls.len(), runs);
+            if runs == 2 {
+                assert_eq!(arg_value(&rofi.calls[0].0, "-mesg"), None);
+                assert_eq!(arg_value(&rofi.calls[1].0, "-mesg"), Some(notice));
+            }
+        }
+    }
+
+    #[test]
+    fn test_numeric_pin() {
+        assert!(super::is_pin_prompt("PIN"));
+        assert!(super::is_pin_prompt("Admin PIN:"));
+        assert!(super::is_pin_prompt("||Reset Code"));
+        assert!(!super::is_pin_prompt("Passphrase:"));
+        assert!(!super::is_pin_prompt("PIN or passphrase"));
+
+        for (extra, commands, reply, runs) in [
+            // Card PINs may contain letters
+            (&[][..], &[("SETPROMPT", "PIN")][..], "D 12ab56\nOK\n", 1),
+            // Unless digits are asked for: letters are rejected and asked again
+            (
+                &["--numeric-card-pin"][..],
+                &[("SETPROMPT", "PIN")][..],
+                "D 123456\nOK\n",
+                2,
+            ),
+            // Explicit option
+            (&[][..], &[("OPTION", "numeric-pin")][..], "D 123456\nOK\n", 2),
+            // Passphrases take anything
+            (
+                &["--numeric-card-pin"][..],
+                &[("SETPROMPT", "Passphrase:")][..],
+                "D 12ab56\nOK\n",
+                1,
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::with_outputs(vec![rofi_output(0, "12ab56\n", ""), rofi_output(0, "123456\n", "")]);
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in commands {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+            assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), reply, "{commands:?}");
+            assert_eq!(rofi.calls.len(), runs);
+            if runs == 2 {
+                assert_eq!(arg_value(&rofi.calls[0].0, "-mesg"), None);
+                assert_eq!(
+                    arg_value(&rofi.calls[1].0, "-mesg"),
+                    Some("The PIN may only contain digits")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_entries_from_file() -> Result<(), Box<dyn std::error::Error>> {
+        use std::fs;
+
+        assert_eq!(
+            super::parse_entries("alice@work\r\n\n  \nbob@home\n"),
+            ["alice@work", "bob@home"]
+        );
+        assert!(super::parse_entries("\n\n").is_empty());
+
+        let dir = env::temp_dir().join(format!("pinentry-rofi-entries-{}", process::id()));
+        fs::create_dir_all(&dir)?;
+        let entries = dir.join("entries");
+        fs::write(&entries, "alice@work\nbob@home\n")?;
+        let empty = dir.join("empty");
+        fs::write(&empty, "\n")?;
+
+        let mut results = Vec::new();
+        for (path, outputs) in [
+            (&entries, vec![rofi_output(0, "bob@home\n", "")]),
+            (&entries, vec![rofi_output(1, "", "")]),
+            (&entries, vec![rofi_output(0, "mallory\n", "")]),
+            (&empty, vec![]),
+        ] {
+            let (output, rofi) = run_getpin_session(
+                &["--entries-from-file", path.to_str().unwrap()],
+                &[],
+                MockRofi::with_outputs(outputs),
+            );
+            results.push((output, rofi.calls));
+        }
+        fs::remove_dir_all(&dir)?;
+
+        let (output, calls) = &results[0];
+        assert_eq!(output, "D bob@home\nOK\n");
+        let (args, input) = &calls[0];
+        assert_eq!(input.as_deref(), Some("alice@work\nbob@home"));
+        assert!(args.contains(&"-no-custom".to_owned()));
+        assert!(!args.contains(&"-password".to_owned()));
+        assert_eq!(arg_value(args, "-l"), Some("2"));
+        assert_eq!(results[1].0, "ERR 83886179 Operation cancelled <rofi>\n");
+        assert_eq!(results[2].0, "ERR 83886179 Operation cancelled <rofi>\n");
+        assert_eq!(
+            results[3].0,
+            format!("ERR 83886179 Operation cancelled <{}: no entries>\n", empty.display())
+        );
+        assert!(results[3].1.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttytype_in_state() {
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&OPTION_VARS);
+        for extra in [&[][..], &["--no-env-options"][..]] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [
+                ("OPTION", "ttyname=/dev/pts/1"),
+                ("OPTION", "ttytype=tmux-256color"),
+                ("GETINFO", "ttyinfo"),
+            ] {
+                super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+            }
+            // Stored whether or not it also reached GPG_TERM
+            assert_eq!(state.options["ttytype"], "tmux-256color");
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                "OK\nOK\nD /dev/pts/1 tmux-256color :0\nOK\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_prompt_template() {
+        let values = HashMap::from([
+            ("prompt", "PIN".to_owned()),
+            ("host", "box".to_owned()),
+            ("user", "alice".to_owned()),
+            ("keygrip", "ABCD".to_owned()),
+        ]);
+        for (template, rendered) in [
+            ("{prompt} ({host})", "PIN (box)"),
+            ("{user}@{host} {keygrip}", "alice@box ABCD"),
+            ("{prompt} {nosuch} {}", "PIN {nosuch} {}"),
+            ("{prompt} {unclosed", "PIN {unclosed"),
+            ("{{prompt}}", "{PIN}"),
+            ("no placeholders", "no placeholders"),
+        ] {
+            assert_eq!(super::render_template(template, &values), rendered, "{template}");
+        }
+
+        let args = test_args(&["--prompt-template", "{prompt} for {keygrip} [{nosuch}]"]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [("SETPROMPT", "PIN:"), ("SETKEYINFO", "n/ABCD"), ("GETPIN", "")] {
+            super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(arg_value(&rofi.calls[0].0, "-p"), Some("PIN for ABCD [{nosuch}]"));
+        assert!(!super::hostname().is_empty());
+    }
+
+    #[test]
+    fn test_end_marker() {
+        let strip = |stdout: &str| {
+            super::strip_end_marker(stdout.as_bytes(), "<<END>>").map(|pin| String::from_utf8(pin.to_vec()).unwrap())
+        };
+        assert_eq!(strip("s3cr3t pass<<END>>\n").as_deref(), Ok("s3cr3t pass"));
+        assert_eq!(strip("<<END>>\n").as_deref(), Ok(""));
+        assert!(strip("s3cr3t pa").unwrap_err().contains("before the end marker"));
+        assert!(strip("a<<END>>b<<END>>\n")
+            .unwrap_err()
+            .contains("contains the end marker"));
+        assert!(strip("a<<END>>b\n").unwrap_err().contains("continues after"));
+
+        assert!(super::parse_end_marker("<<END>>").is_ok());
+        for marker in ["", "--stop--", "a\nb"] {
+            assert!(super::parse_end_marker(marker).is_err(), "{marker:?}");
+        }
+
+        let (output, rofi) = run_getpin_session(
+            &["--end-marker", "<<END>>"],
+            &[],
+            MockRofi::with_outputs(vec![rofi_output(0, "pass<<END>>\n", "")]),
+        );
+        assert_eq!(output, "D pass\nOK\n");
+        assert_eq!(arg_value(&rofi.calls[0].0, "-format"), Some("s<<END>>"));
+        let (output, _) = run_getpin_session(
+            &["--end-marker", "<<END>>"],
+            &[],
+            MockRofi::with_outputs(vec![rofi_output(0, "pa", "")]),
+        );
+        assert_eq!(
+            output,
+            "ERR 83886179 Operation cancelled <rofi output ended before the end marker>\n"
+        );
+    }
+
+    #[test]
+    fn test_assuan_strict_encoding() {
+        for arg in ["", "Unlock key", "Unlock%0Akey %25 done", "%e2%82%ac"] {
+            assert_eq!(super::check_assuan_encoding(arg), Ok(()), "{arg:?}");
+        }
+        for (arg, err) in [
+            ("100%", "bad percent escape at byte 3"),
+            ("%zz", "bad percent escape at byte 0"),
+            ("%0", "bad percent escape at byte 0"),
+            ("tab\there", "raw control character 0x09 at byte 3"),
+            ("bell\x07", "raw control character 0x07 at byte 4"),
+        ] {
+            assert_eq!(super::check_assuan_encoding(arg), Err(err.to_owned()), "{arg:?}");
+        }
+
+        for (extra, reply) in [
+            (&[][..], "OK\n"),
+            (
+                &["--assuan-strict-encoding"][..],
+                "ERR 83886356 IPC syntax error <bad percent escape at byte 3>\n",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut writer = super::Writer::new(Vec::new());
+            super::handle_command(
+                "SETDESC",
+                "100% sure",
+                &mut super::State::default(),
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), reply);
+        }
+    }
+
+    #[test]
+    fn test_attempts_glyphs() {
+        for (try_n, of, glyphs) in [
+            (1, 3, "●○○"),
+            (2, 3, "●●○"),
+            (3, 3, "●●●"),
+            (5, 3, "●●●"),
+            (0, 2, "○○"),
+            (1, 1, "●"),
+            (0, 0, ""),
+            (1, 100, &format!("●{}", "○".repeat(19))),
+        ] {
+            assert_eq!(super::attempts_glyphs(try_n, of), glyphs, "{try_n} of {of}");
+        }
+
+        for (extra, mesg) in [
+            (
+                &[][..],
+                "Bad Passphrase (try 2 of 3)\r***************************\rUnlock",
+            ),
+            (
+                &["--show-attempts"][..],
+                "●●○\rBad Passphrase (try 2 of 3)\r***************************\rUnlock",
+            ),
+        ] {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut rofi = MockRofi::default();
+            let mut writer = super::Writer::new(Vec::new());
+            for (action, arg) in [
+                ("SETDESC", "Unlock"),
+                ("SETERROR", "Bad Passphrase (try 2 of 3)"),
+                ("GETPIN", ""),
+            ] {
+                super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+            }
+            assert_eq!(arg_value(&rofi.calls[0].0, "-mesg"), Some(mesg), "{extra:?}");
+        }
+    }
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn test_fuzz_seeds() -> Result<(), Box<dyn std::error::Error>> {
+        for seed in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/handle_command"))? {
+            super::fuzz_handle_command(&std::fs::read(seed?.path())?);
+        }
+        super::fuzz_handle_command(b"OPTION lc-ctype=%00\nSETDESC %\nSETERROR %FF\n\xff");
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_option_nul() {
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&OPTION_VARS);
+        let mut writer = super::Writer::new(Vec::new());
+        super::handle_command(
+            "OPTION",
+            "ttytype=xterm%00",
+            &mut super::State::default(),
+            &test_args(&[]),
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\n");
+        assert_ne!(env::var("GPG_TERM").ok().as_deref(), Some("xterm\0"));
+    }
+
+    #[test]
+    fn test_env_options() {
+        fn send_lc_ctype(extra: &[&str], value: &str) -> String {
+            let args = test_args(extra);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            let option = format!("lc-ctype={value}");
+            super::handle_command(
+                "OPTION",
+                &option,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+            assert_eq!(state.options["lc-ctype"], value);
+            String::from_utf8(writer.into_inner()).unwrap()
+        }
+
+        let _lock = lock_env();
+        let _env = super::EnvGuard::capture(&OPTION_VARS);
+        assert_eq!(send_lc_ctype(&["--no-env-options"], "xx_OFF.UTF-8"), "OK\n");
+        assert_ne!(env::var("LC_CTYPE").ok().as_deref(), Some("xx_OFF.UTF-8"));
+        send_lc_ctype(&["--env-options", "ttyname,ttytype"], "xx_NOT_ALLOWED.UTF-8");
+        assert_ne!(env::var("LC_CTYPE").ok().as_deref(), Some("xx_NOT_ALLOWED.UTF-8"));
+        send_lc_ctype(&["--env-options", "lc-ctype"], "xx_ALLOWED.UTF-8");
+        assert_eq!(env::var("LC_CTYPE").ok().as_deref(), Some("xx_ALLOWED.UTF-8"));
+
+        assert!(super::cmd()
+            .try_get_matches_from(["pinentry-rofi", "--env-options", "display"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_locale_reaches_rofi() {
+        // Only rofi gets the locale, our environment is left alone
+        let args = test_args(&["--no-env-options"]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [
+            ("GETPIN", ""),
+            ("OPTION", "lc-ctype=xx_CHILD.UTF-8"),
+            ("OPTION", "lc-messages=yy_CHILD"),
+            ("OPTION", "ttyname=/dev/pts/9"),
+            ("GETPIN", ""),
+            ("CONFIRM", ""),
+        ] {
+            super::handle_command(action, arg, &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        let locale = vec![
+            ("LC_CTYPE", "xx_CHILD.UTF-8".to_owned()),
+            ("LC_MESSAGES", "yy_CHILD".to_owned()),
+        ];
+        assert_eq!(rofi.envs, [vec![], locale.clone(), locale.clone()]);
+
+        let output = super::rofi_command("sh", &["-c", "echo \"$LC_CTYPE $LC_MESSAGES\""], false, &locale)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "xx_CHILD.UTF-8 yy_CHILD\n");
+    }
+
+    #[test]
+    fn test_trailing_space_arguments() {
+        let args = test_args(&["--exit-on-first-error"]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [
+            ("GETINFO", " "),
+            ("OPTION", ""),
+            ("GETINFO", "flavor "),
+            ("GETINFO", "nosuchinfo"),
+            ("OPTION", "grab "),
+            ("GETPIN", " "),
+        ] {
+            super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+        }
+        assert_eq!(state.options["grab"], "");
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "ERR 83886360 Invalid GETINFO argument ``\n\
+             ERR 83886360 Invalid OPTION argument ``\n\
+             D keyring\nOK\n\
+             ERR 83886360 Invalid GETINFO argument `nosuchinfo`\n\
+             OK\nOK\n"
+        );
+    }
+
+    #[test]
+    fn test_ok_text() {
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for (action, arg) in [("SETDESC", "Unlock"), ("SETKEYINFO", "n/ABCDEF"), ("BYE", "")] {
+            super::handle_command(action, arg, &mut state, &args, &mut MockRofi::default(), &mut writer).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "OK\nOK\nOK closing connection\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_command_modes() {
+        for (extra, reply, keeps_going) in [
+            (vec![], "ERR 83886355 Unknown IPC command", true),
+            (vec!["--exit-on-first-error"], "BYE", false),
+        ] {
+            let args = test_args(&extra);
+            let mut state = super::State::default();
+            let mut writer = super::Writer::new(Vec::new());
+            let result = super::handle_command(
+                "NOSUCHCMD",
+                "",
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            );
+            assert_eq!(result.is_ok(), keeps_going);
+            if keeps_going {
+                super::handle_command(
+                    "GETINFO",
+                    "flavor",
+                    &mut state,
+                    &args,
+                    &mut MockRofi::default(),
+                    &mut writer,
+                )
+                .unwrap();
+                assert_eq!(
+                    String::from_utf8(writer.into_inner()).unwrap(),
+                    format!("{reply}\nD keyring\nOK\n")
+                );
+            } else {
+                assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), format!("{reply}\n"));
+            }
+        }
+
+        // SETREPEAT is known, only refused
+        let mut rofi = MockRofi::default();
+        let output = crate::testing::run_session(&["--exit-on-first-error"], "SETREPEAT\nBYE\n", &mut rofi).unwrap();
+        assert_eq!(
+            output,
+            "OK Please go ahead\nERR 83886140 Not supported <no repeat entry>\nOK closing connection\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_command_redacts_secret() {
+        let args = test_args(&["--exit-on-first-error"]);
+        let mut writer = super::Writer::new(Vec::new());
+        let err = super::handle_command(
+            "SETPASS",
+            "pin=Zx8Kq2Lm9Pw4Rt7Vb3Nc",
+            &mut super::State::default(),
+            &args,
+            &mut MockRofi::default(),
+            &mut writer,
+        )
+        .unwrap_err();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "# Argument of SETPASS looks like a secret, redacted\nBYE\n"
+        );
+        assert!(!format!("{err} {err:?}").contains("Zx8Kq2Lm9Pw4Rt7Vb3Nc"));
+    }
+
+    #[test]
+    fn test_option_secret_redacted() {
+        let capture = LogCapture::start();
+        let mut rofi = MockRofi::default();
+        let script = "OPTION owner=Zx8Kq2Lm9Pw4Rt7Vb3Nc\nOPTION default-ok=Yes\nCONFIRM Zx8Kq2Lm9Pw4Rt7Vb3Nc\n";
+        let output = crate::testing::run_session(&[], script, &mut rofi).unwrap();
+        assert_eq!(
+            output,
+            "OK Please go ahead\n\
+             # Value of OPTION owner looks like a secret, redacted\nOK\n\
+             OK\n\
+             ERR 83886360 Invalid CONFIRM argument `Zx8Kq2Lm9Pw4Rt7Vb3Nc`\n"
+        );
+        // The client still gets its own words back, only the log leaves them out
+        let lines = capture.lines();
+        assert!(
+            lines.iter().all(|line| !line.contains("Zx8Kq2Lm9Pw4Rt7Vb3Nc")),
+            "{lines:?}"
+        );
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.ends_with("-> ERR 83886360 Invalid CONFIRM argument [redacted]")),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn test_build_rofi_command() {
+        let base = HashMap::from([
             ("-dmenu".to_owned(), None),
-            ("-display".to_owned(), Some(":0".to_owned())),
             ("-input".to_owned(), Some("/dev/null".to_owned())),
             ("-password".to_owned(), None),
-            ("-disable-history".to_owned(), None),
-            ("-l".to_owned(), Some("0".to_owned())),
+            ("-p".to_owned(), Some("Passphrase".to_owned())),
         ]);
+        let expected = ["-dmenu", "-input", "/dev/null", "-p", "Passphrase", "-password"];
+        assert_eq!(super::build_rofi_command(&base, &test_args(&[])), expected);
 
-        if let Some(prompt) = custom_prompt {
-            assuan_mapping[19].etalon_rofi_args = Some(HashMap::from([
-                ("-dmenu".to_owned(), None),
-                ("-display".to_owned(), Some(":0".to_owned())),
-                ("-input".to_owned(), Some("/dev/null".to_owned())),
-                ("-password".to_owned(), None),
-                ("-disable-history".to_owned(), None),
-                ("-l".to_owned(), Some("0".to_owned())),
-                ("-p".to_owned(), Some(prompt.to_owned())),
-            ]));
-            assuan_mapping[20].etalon_rofi_args = Some(HashMap::from([
-                ("-dmenu".to_owned(), None),
-                ("-display".to_owned(), Some(":0".to_owned())),
-                ("-input".to_owned(), Some("/dev/null".to_owned())),
-                ("-password".to_owned(), None),
-                ("-disable-history".to_owned(), None),
-                ("-l".to_owned(), Some("0".to_owned())),
-                ("-p".to_owned(), Some(prompt.to_owned())),
-                (
-                    "-mesg".to_owned(),
-                    Some(
-                        "Please enter the passphrase for the ssh key\r  \
-                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                            .to_owned(),
-                    ),
-                ),
-            ]));
-            assuan_mapping[21].etalon_rofi_args = Some(HashMap::from([
-                ("-dmenu".to_owned(), None),
-                ("-display".to_owned(), Some(":0".to_owned())),
-                ("-input".to_owned(), Some("/dev/null".to_owned())),
-                ("-password".to_owned(), None),
-                ("-disable-history".to_owned(), None),
-                ("-l".to_owned(), Some("0".to_owned())),
-                ("-p".to_owned(), Some(prompt.to_owned())),
-                (
-                    "-mesg".to_owned(),
-                    Some(
-                        "Please enter the passphrase for the ssh key\r  \
-                                                ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                            .to_owned(),
-                    ),
-                ),
-            ]));
-            assuan_mapping[22].etalon_rofi_args = Some(HashMap::from([
+        // Per-dialog additions sort in with the base ones
+        let mut dialog = base.clone();
+        dialog.insert("-mesg".to_owned(), Some("Unlock".to_owned()));
+        dialog.insert(
+            "-theme-str".to_owned(),
+            Some("entry { placeholder: \"PIN\"; }".to_owned()),
+        );
+        assert_eq!(
+            super::build_rofi_command(&dialog, &test_args(&[])),
+            [
+                "-dmenu",
+                "-input",
+                "/dev/null",
+                "-mesg",
+                "Unlock",
+                "-p",
+                "Passphrase",
+                "-password",
+                "-theme-str",
+                "entry { placeholder: \"PIN\"; }",
+            ]
+        );
+
+        // Flag-driven arguments come last, `--theme-str` in the given order
+        let args = test_args(&[
+            "--theme-str",
+            "b { }",
+            "--theme-str",
+            "a { }",
+            "--foreground",
+            "--ime-friendly",
+        ]);
+        assert_eq!(
+            super::build_rofi_command(&base, &args),
+            [
+                &expected[..],
+                &[
+                    "-steal-focus",
+                    "-normal-window",
+                    "-theme-str",
+                    "b { }",
+                    "-theme-str",
+                    "a { }"
+                ],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_max_mesg_width() {
+        let base = HashMap::from([("-dmenu".to_owned(), None)]);
+        assert_eq!(super::build_rofi_command(&base, &test_args(&[])), ["-dmenu"]);
+        assert_eq!(
+            super::build_rofi_command(
+                &base,
+                &test_args(&["--max-mesg-width", "60", "--mesg-color", "red", "--theme-str", "a { }"])
+            ),
+            [
+                "-dmenu",
+                "-theme-str",
+                "textbox { text-color: red; }",
+                "-theme-str",
+                "window { width: 60ch; }",
+                "-theme-str",
+                "a { }"
+            ]
+        );
+        for width in ["0", "-1", "wide"] {
+            assert!(super::cmd()
+                .try_get_matches_from(["pinentry-rofi", "--max-mesg-width", width])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_temp_input() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rofi_args = super::base_rofi_args(&test_args(&[]))?;
+        let input = super::use_temp_input(&mut rofi_args)?;
+        let path = std::path::PathBuf::from(rofi_args["-input"].clone().unwrap());
+        assert_eq!(path, input.path());
+        assert_eq!(std::fs::read(&path)?, b"");
+
+        // Each run gets its own
+        let mut other_args = rofi_args.clone();
+        let other = super::use_temp_input(&mut other_args)?;
+        assert_ne!(other_args["-input"], rofi_args["-input"]);
+
+        drop(input);
+        assert!(!path.exists());
+        drop(other);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rofi_config() -> Result<(), Box<dyn std::error::Error>> {
+        let rofi_args = super::base_rofi_args(&test_args(&[]))?;
+        assert!(!rofi_args.contains_key("-config"));
+
+        let config = env::temp_dir().join(format!("pinentry-rofi-config-{}.rasi", process::id()));
+        std::fs::write(&config, "configuration { }\n")?;
+        let rofi_args = super::base_rofi_args(&test_args(&["--rofi-config", config.to_str().unwrap()]))?;
+        assert_eq!(rofi_args["-config"].as_deref(), config.to_str());
+        assert_eq!(rofi_args["-dmenu"], None);
+        std::fs::remove_file(&config)?;
+
+        assert_eq!(
+            super::base_rofi_args(&test_args(&["--rofi-config", config.to_str().unwrap()])),
+            Err(format!("--rofi-config `{}` is not a file", config.display()))
+        );
+        assert!(super::base_rofi_args(&test_args(&["--rofi-config", "/"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_colors() {
+        use super::parse_color;
+
+        for color in ["#fff", "#FFFA", "#12ab34", "#12ab34cc", "red", "DarkSlateGray"] {
+            assert_eq!(parse_color(color).as_deref(), Ok(color));
+        }
+        for color in ["", "#", "#ff", "#fffff", "#12ab3g", "red; }", "rgb(1,2,3)", "dark-red"] {
+            assert!(parse_color(color).is_err(), "{color}");
+        }
+        assert!(super::cmd()
+            .try_get_matches_from(["pinentry-rofi", "--prompt-color", "red; } * {"])
+            .is_err());
+
+        let base = HashMap::from([("-dmenu".to_owned(), None)]);
+        for (extra, theme) in [
+            (&[][..], None),
+            (
+                &["--prompt-color", "#ff0000"][..],
+                Some("prompt { text-color: #ff0000; }"),
+            ),
+            (&["--mesg-color", "gray"][..], Some("textbox { text-color: gray; }")),
+            (
+                &["--mesg-color", "gray", "--prompt-color", "#f00"][..],
+                Some("prompt { text-color: #f00; } textbox { text-color: gray; }"),
+            ),
+        ] {
+            let command = super::build_rofi_command(&base, &test_args(extra));
+            assert_eq!(arg_value(&command, "-theme-str"), theme, "{extra:?}");
+        }
+
+        // Before `--theme-str`, which can override them
+        let args = test_args(&["--theme-str", "prompt { text-color: blue; }", "--prompt-color", "red"]);
+        assert_eq!(
+            super::build_rofi_command(&base, &args),
+            [
+                "-dmenu",
+                "-theme-str",
+                "prompt { text-color: red; }",
+                "-theme-str",
+                "prompt { text-color: blue; }"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prompt_bidi() {
+        use super::{bidi_marked, PromptBidi};
+
+        const LRM: &str = "\u{200E}";
+        const RLM: &str = "\u{200F}";
+
+        assert_eq!(bidi_marked("שלום", PromptBidi::Rtl), format!("{RLM}שלום"));
+        assert_eq!(
+            bidi_marked("סיסמה\r123 key\nمرحبا", PromptBidi::Rtl),
+            format!("{RLM}סיסמה\r{RLM}123 key\n{RLM}مرحبا")
+        );
+        assert_eq!(bidi_marked("Passphrase", PromptBidi::Ltr), format!("{LRM}Passphrase"));
+        assert_eq!(bidi_marked("", PromptBidi::Rtl), RLM);
+        // The first letter decides, past digits, tags and entities
+        assert_eq!(
+            bidi_marked("2 <b>&quot;מפתח&quot;</b>\rkey", PromptBidi::Auto),
+            format!("{RLM}2 <b>&quot;מפתח&quot;</b>\r{RLM}key")
+        );
+        assert_eq!(
+            bidi_marked("Key מפתח\rשלום", PromptBidi::Auto),
+            format!("{LRM}Key מפתח\r{LRM}שלום")
+        );
+        assert_eq!(bidi_marked("1234", PromptBidi::Auto), "1234");
+
+        let rofi_args = HashMap::from([
+            ("-p".to_owned(), Some("סיסמה".to_owned())),
+            ("-mesg".to_owned(), Some("ביטול\rאישור".to_owned())),
+            ("-input".to_owned(), Some("/dev/null".to_owned())),
+        ]);
+        assert_eq!(
+            super::build_rofi_command(&rofi_args, &test_args(&["--prompt-bidi", "rtl"])),
+            [
+                "-input".to_owned(),
+                "/dev/null".to_owned(),
+                "-mesg".to_owned(),
+                format!("{RLM}ביטול\r{RLM}אישור"),
+                "-p".to_owned(),
+                format!("{RLM}סיסמה"),
+            ]
+        );
+        assert_eq!(
+            super::build_rofi_command(&rofi_args, &test_args(&[])),
+            ["-input", "/dev/null", "-mesg", "ביטול\rאישור", "-p", "סיסמה"]
+        );
+    }
+
+    #[test]
+    fn test_rofi_command() -> Result<(), Box<dyn std::error::Error>> {
+        let command = super::rofi_command("rofi", &["-dmenu", "-p", "Passphrase"], false, &[]);
+        assert_eq!(command.get_program(), "rofi");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-dmenu", "-p", "Passphrase"]);
+
+        // The child leads its own process group
+        let output = super::rofi_command("sh", &["-c", "echo $$; cut -d' ' -f5 /proc/$$/stat"], false, &[]).output()?;
+        let output = String::from_utf8(output.stdout)?;
+        let (pid, pgid) = output.trim_end().split_once('\n').unwrap();
+        assert_eq!(pid, pgid);
+        assert_ne!(pgid, format!("{}", process::id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rofi_outcome() {
+        use super::RofiOutcome;
+
+        match RofiOutcome::from(rofi_output(0, "s3cr3t pass \n", "")) {
+            RofiOutcome::Pin(pin) => assert_eq!(*pin, "s3cr3t pass "),
+            outcome => panic!("{outcome:?}"),
+        }
+        assert!(matches!(
+            RofiOutcome::from(rofi_output(0, "\n", "")),
+            RofiOutcome::Empty
+        ));
+        assert!(matches!(RofiOutcome::from(rofi_output(0, "", "")), RofiOutcome::Empty));
+        assert!(matches!(
+            RofiOutcome::from(rofi_output(1, "", "")),
+            RofiOutcome::Cancelled
+        ));
+        match RofiOutcome::from(rofi_output(1, "", "Failed to grab keyboard\n")) {
+            RofiOutcome::Error(err) => assert_eq!(err, "Failed to grab keyboard"),
+            outcome => panic!("{outcome:?}"),
+        }
+        match RofiOutcome::from(rofi_output(2, "", "")) {
+            RofiOutcome::Error(err) => assert_eq!(err, "exit status: 2"),
+            outcome => panic!("{outcome:?}"),
+        }
+        let mut invalid = rofi_output(0, "", "");
+        invalid.stdout = vec![0xff, 0xfe];
+        assert!(matches!(RofiOutcome::from(invalid), RofiOutcome::Error(_)));
+        for (raw, stderr) in [(libc::SIGSEGV, ""), (libc::SIGKILL, "partial output\n")] {
+            let mut killed = rofi_output(0, "", stderr);
+            killed.status = process::ExitStatus::from_raw(raw);
+            match RofiOutcome::from(killed) {
+                RofiOutcome::Killed(signal) => assert_eq!(signal, raw),
+                outcome => panic!("{outcome:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_theme_error() {
+        assert_eq!(
+            super::theme_error("<big><b>Error while parsing theme:</b></big> <i>window {</i>\n").as_deref(),
+            Some("Error while parsing theme: window {")
+        );
+        assert_eq!(
+            super::theme_error("Rofi started\nFailed to load theme: nosuch.rasi\n").as_deref(),
+            Some("Failed to load theme: nosuch.rasi")
+        );
+        assert_eq!(super::theme_error("Failed to grab keyboard\n"), None);
+        assert_eq!(super::theme_error("Using theme dracula\n"), None);
+
+        // rofi failing on the theme is not a cancel
+        match super::RofiOutcome::from(rofi_output(1, "", "Error while parsing theme: line 3\n")) {
+            super::RofiOutcome::Error(err) => assert_eq!(err, "rofi theme error: Error while parsing theme: line 3"),
+            outcome => panic!("{outcome:?}"),
+        }
+        // A theme warning doesn't throw away a passphrase rofi went on to accept
+        match super::RofiOutcome::from(rofi_output(0, "pass\n", "Error while parsing theme: line 3\n")) {
+            super::RofiOutcome::Pin(pin) => assert_eq!(*pin, "pass"),
+            outcome => panic!("{outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn test_getpin_outcomes() {
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut rofi = MockRofi::with_outputs(vec![
+            rofi_output(0, "pass\n", ""),
+            rofi_output(0, "\n", ""),
+            rofi_output(1, "", ""),
+            rofi_output(1, "", "Failed to grab keyboard"),
+            process::Output {
+                status: process::ExitStatus::from_raw(libc::SIGSEGV),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            },
+        ]);
+        let mut writer = super::Writer::new(Vec::new());
+        for _ in 0..5 {
+            super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "D pass\nOK\nOK\n\
+             ERR 83886179 Operation cancelled <rofi>\n\
+             ERR 83886179 Operation cancelled <Failed to grab keyboard>\n\
+             ERR 83886081 General error <rofi terminated by signal 11>\n"
+        );
+
+        // A killed CONFIRM is no "Cancel" either
+        let killed = process::Output {
+            status: process::ExitStatus::from_raw(libc::SIGKILL),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+        let (output, _) = run_confirm_session_with_args("", MockRofi::with_outputs(vec![killed]), &[], &[]);
+        assert_eq!(output, "ERR 83886081 General error <rofi terminated by signal 9>\n");
+    }
+
+    fn run_getpin_session(extra: &[&str], options: &[&str], mut rofi: MockRofi) -> (String, MockRofi) {
+        let args = test_args(extra);
+        let mut state = super::State {
+            rofi_args: HashMap::from([
                 ("-dmenu".to_owned(), None),
-                ("-display".to_owned(), Some(":0".to_owned())),
                 ("-input".to_owned(), Some("/dev/null".to_owned())),
                 ("-password".to_owned(), None),
-                ("-disable-history".to_owned(), None),
-                ("-l".to_owned(), Some("0".to_owned())),
-                ("-p".to_owned(), Some(prompt.to_owned())),
-                (
-                    "-mesg".to_owned(),
-                    Some(
-                        "Bad Passphrase (try 2 of 3)\r***************************\r\
-                            Please enter the passphrase for the ssh key\r  \
-                            ke:yf:in:ge:rp:ri:nt &quot;&lt;email@yhoo.com&gt;&quot;"
-                            .to_owned(),
-                    ),
-                ),
-            ]));
-            rofi_args.insert("-p".to_owned(), Some(prompt.to_owned()));
+            ]),
+            ..Default::default()
+        };
+        let mut writer = super::Writer::new(Vec::new());
+        for option in options {
+            super::handle_command("OPTION", option, &mut state, &args, &mut rofi, &mut writer).unwrap();
         }
-        (rofi_args, assuan_mapping)
+        super::handle_command("GETPIN", "", &mut state, &args, &mut rofi, &mut writer).unwrap();
+        (String::from_utf8(writer.into_inner()).unwrap(), rofi)
     }
 
-    fn process_test_handle_command(custom_prompt: Option<String>) -> Result<(), Box<(dyn std::error::Error)>> {
-        let (mut rofi_args, assuan_mapping) = prepare_test_handle_command(custom_prompt);
-        let is_test = true;
-        let mut buf = Vec::new();
-        let mut etalon_output = String::new();
-        {
-            let mut writer = super::Writer {
-                writer: Box::new(&mut buf),
-            };
-            for entry in assuan_mapping.iter() {
-                let (action, arg) = entry.cmd.split_once(' ').unwrap_or_else(|| (&entry.cmd, ""));
-                if let Err(e) = super::handle_command(action, arg, &mut rofi_args, &mut writer, &is_test) {
-                    assert!(format!("{}", e).contains("Unknown assuan command"))
-                }
+    fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+        let pos = args.iter().position(|arg| arg == flag)?;
+        args.get(pos + 1).map(String::as_str)
+    }
 
-                etalon_output.push_str(&entry.etalon_output);
-                etalon_output.push_str("\n");
+    #[test]
+    fn test_show_passphrase_confirmed() {
+        let (output, rofi) = run_getpin_session(
+            &["--kb-show-passphrase", "Alt+v"],
+            &["default-cf-visi=Really show it%3F"],
+            MockRofi::with_outputs(vec![
+                rofi_output(10, "typed so far\n", ""),
+                rofi_output(0, "OK\n", ""),
+                rofi_output(0, "pass\n", ""),
+            ]),
+        );
+        assert_eq!(output, "OK\nD pass\nOK\n");
+        assert_eq!(rofi.calls.len(), 3);
+        let (masked, _) = &rofi.calls[0];
+        assert!(masked.contains(&"-password".to_owned()));
+        assert_eq!(arg_value(masked, "-kb-custom-1"), Some("Alt+v"));
+        let (confirm, input) = &rofi.calls[1];
+        assert_eq!(arg_value(confirm, "-mesg"), Some("Really show it?"));
+        assert_eq!(input.as_deref(), Some("OK\nCancel"));
+        let (unmasked, _) = &rofi.calls[2];
+        assert!(!unmasked.contains(&"-password".to_owned()));
+    }
 
-                if let Some(etalon) = &entry.etalon_rofi_args {
-                    assert_eq!(etalon.to_owned(), rofi_args, "Action: {action}. Arg: {arg}");
-                }
-            }
+    #[test]
+    fn test_constraints_help() {
+        let options = [
+            "constraints-hint-short=At least 12 characters",
+            "constraints-hint-long=At least 12 characters%0ANo dictionary words & no names",
+        ];
+        let (output, rofi) = run_getpin_session(
+            &["--kb-constraints-help", "Alt+h"],
+            &options,
+            MockRofi::with_outputs(vec![
+                rofi_output(11, "typed so far\n", ""),
+                rofi_output(0, "OK\n", ""),
+                rofi_output(0, "pass\n", ""),
+            ]),
+        );
+        assert_eq!(output, "OK\nOK\nD pass\nOK\n");
+        assert_eq!(rofi.calls.len(), 3);
+        let (prompt, _) = &rofi.calls[0];
+        assert_eq!(arg_value(prompt, "-kb-custom-2"), Some("Alt+h"));
+        assert_eq!(
+            arg_value(prompt, "-mesg"),
+            Some("At least 12 characters\rAlt+h: Passphrase rules")
+        );
+        let (help, input) = &rofi.calls[1];
+        assert_eq!(
+            arg_value(help, "-mesg"),
+            Some("At least 12 characters\rNo dictionary words &amp; no names")
+        );
+        assert_eq!(input.as_deref(), Some("OK"));
+        assert_eq!(rofi.calls[2].0, rofi.calls[0].0);
+
+        // Only the short hint without the key, or without a long hint to show
+        for (extra, options) in [
+            (&[][..], &options[..]),
+            (&["--kb-constraints-help", "Alt+h"][..], &options[..1]),
+        ] {
+            let (output, rofi) = run_getpin_session(extra, options, MockRofi::default());
+            assert_eq!(output, "OK\n".repeat(options.len() + 1), "{extra:?}");
+            let (prompt, _) = &rofi.calls[0];
+            assert_eq!(arg_value(prompt, "-kb-custom-2"), None, "{extra:?}");
+            assert_eq!(arg_value(prompt, "-mesg"), Some("At least 12 characters"), "{extra:?}");
         }
-        let output = std::str::from_utf8(buf.as_slice())?.to_owned();
-        assert_eq!(etalon_output, output);
+    }
+
+    #[test]
+    fn test_show_passphrase_declined() {
+        let (output, rofi) = run_getpin_session(
+            &["--kb-show-passphrase", "Alt+v"],
+            &[],
+            MockRofi::with_outputs(vec![
+                rofi_output(10, "", ""),
+                rofi_output(0, "Cancel\n", ""),
+                rofi_output(0, "pass\n", ""),
+            ]),
+        );
+        assert_eq!(output, "D pass\nOK\n");
+        assert_eq!(arg_value(&rofi.calls[1].0, "-mesg"), Some(super::DEFAULT_CF_VISI));
+        assert!(rofi.calls[2].0.contains(&"-password".to_owned()));
+    }
+
+    #[test]
+    fn test_pin_visibility_transitions() -> std::io::Result<()> {
+        use super::PinVisibility::{Hidden, Visible};
+
+        assert_eq!(Hidden.toggled(|| Ok(true))?, Visible);
+        assert_eq!(Hidden.toggled(|| Ok(false))?, Hidden);
+        assert_eq!(Visible.toggled(|| panic!("hiding needs no confirmation"))?, Hidden);
+        assert!(Hidden.toggled(|| Err(std::io::ErrorKind::Other.into())).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_handle_command() -> Result<(), Box<(dyn std::error::Error)>> {
+    fn test_show_passphrase_toggle_back() {
+        let (output, rofi) = run_getpin_session(
+            &["--kb-show-passphrase", "Alt+v"],
+            &["default-tt-visi=Show it", "default-tt-hide=Hide %3Cit%3E"],
+            MockRofi::with_outputs(vec![
+                rofi_output(10, "", ""),
+                rofi_output(0, "OK\n", ""),
+                rofi_output(10, "", ""),
+                rofi_output(0, "pass\n", ""),
+            ]),
+        );
+        assert_eq!(output, "OK\nOK\nD pass\nOK\n");
+        // masked, confirm, unmasked, masked again without asking
+        assert_eq!(rofi.calls.len(), 4);
+        let hints: Vec<_> = [0, 2, 3]
+            .iter()
+            .map(|&i| arg_value(&rofi.calls[i].0, "-mesg"))
+            .collect();
+        assert_eq!(
+            hints,
+            [
+                Some("Alt+v: Show it"),
+                Some("Alt+v: Hide &lt;it&gt;"),
+                Some("Alt+v: Show it")
+            ]
+        );
+        assert!(rofi.calls[3].0.contains(&"-password".to_owned()));
+    }
+
+    #[test]
+    fn test_tooltip_options() {
+        use super::PinVisibility;
+
+        let args = test_args(&[]);
+        let mut state = super::State::default();
+        let mut writer = super::Writer::new(Vec::new());
+        for option in ["default-tt-visi=Passphrase%20anzeigen", "default-tt-hide="] {
+            super::handle_command(
+                "OPTION",
+                option,
+                &mut state,
+                &args,
+                &mut MockRofi::default(),
+                &mut writer,
+            )
+            .unwrap();
+        }
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "OK\nOK\n");
+        assert_eq!(state.options["default-tt-visi"], "Passphrase anzeigen");
+        assert_eq!(state.options["default-tt-hide"], "");
+        assert_eq!(PinVisibility::Hidden.hint(&state), "Passphrase anzeigen");
+        // Empty falls back to the built-in hint
+        assert_eq!(PinVisibility::Visible.hint(&state), "Hide passphrase");
+    }
+
+    #[test]
+    fn test_show_passphrase_unbound() {
+        let (output, rofi) = run_getpin_session(&[], &[], MockRofi::with_outputs(vec![rofi_output(10, "", "")]));
+        assert_eq!(output, "ERR 83886179 Operation cancelled <rofi>\n");
+        assert_eq!(rofi.calls.len(), 1);
+        assert_eq!(arg_value(&rofi.calls[0].0, "-kb-custom-1"), None);
+    }
+
+    #[test]
+    fn test_gpg_error() {
+        use super::{gpg_error, GPG_ERR_CANCELED, GPG_ERR_SOURCE_PINENTRY};
+
+        assert_eq!(gpg_error(GPG_ERR_SOURCE_PINENTRY, GPG_ERR_CANCELED), 83886179);
+        // GPG_ERR_NOT_CONFIRMED
+        assert_eq!(gpg_error(GPG_ERR_SOURCE_PINENTRY, 114), 83886194);
+        // GPG_ERR_TIMEOUT
+        assert_eq!(gpg_error(GPG_ERR_SOURCE_PINENTRY, 62), 83886142);
+        // Source and code are masked to their bit widths
+        assert_eq!(gpg_error(0x85, 0x1_0063), 83886179);
+    }
+
+    #[test]
+    fn test_handle_command() -> Result<(), Box<dyn std::error::Error>> {
         process_test_handle_command(None)?;
         Ok(())
     }
 
     #[test]
-    fn test_handle_command_custom_prompt() -> Result<(), Box<(dyn std::error::Error)>> {
+    fn test_handle_command_custom_prompt() -> Result<(), Box<dyn std::error::Error>> {
         process_test_handle_command(Some("custom-prompt".to_owned()))?;
         Ok(())
     }
@@ -0,0 +1,124 @@
+//! Helpers for testing programs that wrap or embed pinentry-rofi, without rofi or a display.
+//!
+//! [`MockRofi`] stands in for rofi with canned answers, and [`run_session`] drives a whole
+//! Assuan session through it, returning everything pinentry-rofi wrote back.
+//!
+//! ```
+//! use pinentry_rofi::testing::{run_session, MockRofi};
+//!
+//! let mut rofi = MockRofi::new().answer(0, "secret\n");
+//! let transcript = run_session(&["--display", ":9"], "SETDESC Unlock%0Athe key\nGETPIN\nBYE\n", &mut rofi).unwrap();
+//! assert_eq!(transcript, "OK Please go ahead\nOK\nD secret\nOK\nOK closing connection\n");
+//!
+//! // What rofi was started with
+//! let (args, _input) = &rofi.calls()[0];
+//! assert!(args.windows(2).any(|pair| pair == ["-mesg", "Unlock\rthe key"]));
+//! ```
+
+use crate::{cmd, initial_state, serve, BoxResult, Rofi, RofiArgs, Writer};
+use clap::FromArgMatches;
+use std::{
+    io,
+    os::unix::process::ExitStatusExt,
+    process::{ExitStatus, Output},
+    time::Duration,
+};
+
+/// rofi stand-in answering with queued outputs, first queued first, and recording how it was
+/// started. Once the queue runs out it answers as if the user submitted nothing.
+#[derive(Debug, Default)]
+pub struct MockRofi {
+    pub(crate) outputs: Vec<Output>,
+    pub(crate) calls: Vec<(Vec<String>, Option<String>)>,
+    pub(crate) timeouts: Vec<Option<Duration>>,
+    pub(crate) envs: Vec<Vec<(&'static str, String)>>,
+    /// Fail every run as if the timeout expired
+    pub(crate) time_out: bool,
+    /// Fail every run as if the rofi binary were missing
+    pub(crate) not_found: bool,
+    /// Report the display as unusable
+    pub(crate) no_display: bool,
+    pub(crate) masking_warning: Option<String>,
+}
+
+pub(crate) fn rofi_output(code: i32, stdout: &str, stderr: &str) -> Output {
+    Output {
+        status: ExitStatus::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: stderr.as_bytes().to_vec(),
+    }
+}
+
+impl MockRofi {
+    pub fn new() -> Self {
+        MockRofi::default()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_outputs(mut outputs: Vec<Output>) -> Self {
+        outputs.reverse();
+        MockRofi {
+            outputs,
+            ..Default::default()
+        }
+    }
+
+    /// Queue rofi exiting with `code` after printing `stdout`: 0 for a submitted entry, 1 for
+    /// Escape, 10 and up for `-kb-custom-N`
+    pub fn answer(self, code: i32, stdout: &str) -> Self {
+        self.answer_with_stderr(code, stdout, "")
+    }
+
+    /// Like [`answer`](Self::answer), with `stderr` printed too, as rofi does when it fails
+    pub fn answer_with_stderr(mut self, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.outputs.insert(0, rofi_output(code, stdout, stderr));
+        self
+    }
+
+    /// The arguments and the standard input, for menus, of every rofi run so far
+    pub fn calls(&self) -> &[(Vec<String>, Option<String>)] {
+        &self.calls
+    }
+}
+
+impl Rofi for MockRofi {
+    fn run(
+        &mut self,
+        args: &[String],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+        env: &[(&'static str, String)],
+    ) -> io::Result<Output> {
+        self.calls.push((args.to_vec(), input.map(str::to_owned)));
+        self.timeouts.push(timeout);
+        self.envs.push(env.to_vec());
+        if self.time_out {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if self.not_found {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(self.outputs.pop().unwrap_or(rofi_output(0, "", "")))
+    }
+
+    fn display_available(&self, _display: &str) -> bool {
+        !self.no_display
+    }
+
+    fn masking_warning(&mut self) -> Option<String> {
+        self.masking_warning.clone()
+    }
+}
+
+/// Run pinentry-rofi with the command line `args` on the Assuan commands in `script`, one per
+/// line, and return what it answered. The dialogs are set up as `pinentry` sets them up, so
+/// `--prompt`, `--monitor`, `--keygrip-map` and `--prompt-history-salt` apply. Command line
+/// errors come back as errors instead of exiting.
+pub fn run_session(args: &[&str], script: &str, rofi: &mut MockRofi) -> BoxResult<String> {
+    let matches = cmd().try_get_matches_from(std::iter::once("pinentry-rofi").chain(args.iter().copied()))?;
+    let args = RofiArgs::from_arg_matches(&matches)?;
+    let (mut state, _input) = initial_state(&args, None)?;
+    let mut writer = Writer::new(Vec::new());
+    serve(script.as_bytes(), &mut state, &args, rofi, &mut writer)?;
+    Ok(String::from_utf8(writer.into_inner())?)
+}